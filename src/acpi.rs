@@ -0,0 +1,313 @@
+use crate::{boot, debug_println, info_println, return_if};
+use alloc::vec::Vec;
+use spin::Once;
+
+/// Every system description table starts with a 36-byte header.
+const HEADER_SIZE: usize = 36;
+
+pub struct Tables {
+    pub madt: Option<Madt>,
+    pub hpet: Option<Hpet>,
+    pub fadt: Option<Fadt>,
+}
+
+/// The interrupt controllers, from the "APIC" table.
+pub struct Madt {
+    /// Physical address of every CPU's Local APIC registers.
+    pub local_apic_address: u64,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+}
+
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Disabled processors can't be started.
+    pub enabled: bool,
+}
+
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// The first global system interrupt this I/O APIC handles.
+    pub interrupt_base: u32,
+}
+
+/// The High Precision Event Timer, from the "HPET" table.
+pub struct Hpet {
+    /// Physical address of the timer's registers.
+    pub address: u64,
+}
+
+/// The Fixed ACPI Description Table, from the "FACP" table.
+pub struct Fadt {
+    /// The CMOS register that holds the century, or 0 if there isn't one.
+    pub century: u8,
+    /// The port that switches the machine from legacy mode into ACPI mode, and what to write there.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    /// The I/O ports of the power management control registers, or 0 if there isn't a second one.
+    pub pm1a_control: u32,
+    pub pm1b_control: u32,
+    /// The register that resets the machine and what to write to it, if the FADT has one.
+    pub reset: Option<(GenericAddress, u8)>,
+    /// The SLP_TYPa and SLP_TYPb values for soft-off, from the `_S5_` object in the DSDT.
+    pub sleep_type_s5: Option<(u16, u16)>,
+}
+
+/// Where a register is, in one of several address spaces.
+#[derive(Clone, Copy)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub address: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    Memory,
+    Io,
+    Other(u8),
+}
+
+static TABLES: Once<Tables> = Once::new();
+
+/// Finds the ACPI tables through the RSDP Limine hands over and parses the ones the kernel uses.
+///
+/// # Safety
+///
+/// Tables with a bad checksum are skipped. Does nothing on machines without ACPI.
+pub fn acpi_init() {
+    let Some(rsdp) = boot::rsdp() else {
+        info_println!("acpi: no rsdp");
+        return;
+    };
+    let tables = TABLES.call_once(|| parse(rsdp));
+    if let Some(madt) = &tables.madt {
+        for apic in madt.local_apics.iter().filter(|apic| apic.enabled) {
+            debug_println!("acpi: cpu {} apic {}", apic.processor_id, apic.apic_id);
+        }
+        for io_apic in &madt.io_apics {
+            debug_println!(
+                "acpi: io apic {} at {:#x} from irq {}",
+                io_apic.id,
+                io_apic.address,
+                io_apic.interrupt_base
+            );
+        }
+    }
+    if let Some(hpet) = &tables.hpet {
+        debug_println!("acpi: hpet at {:#x}", hpet.address);
+    }
+}
+
+/// Returns the MADT, if `acpi_init` found one.
+pub fn madt() -> Option<&'static Madt> {
+    TABLES.get()?.madt.as_ref()
+}
+
+/// Returns the HPET table, if `acpi_init` found one.
+pub fn hpet() -> Option<&'static Hpet> {
+    TABLES.get()?.hpet.as_ref()
+}
+
+/// Returns the FADT, if `acpi_init` found one.
+pub fn fadt() -> Option<&'static Fadt> {
+    TABLES.get()?.fadt.as_ref()
+}
+
+fn parse(rsdp: u64) -> Tables {
+    let mut tables = Tables {
+        madt: None,
+        hpet: None,
+        fadt: None,
+    };
+    for address in root_entries(rsdp) {
+        let Some(table) = table(address) else {
+            continue;
+        };
+        match &table[..4] {
+            b"APIC" => tables.madt = Some(parse_madt(table)),
+            b"HPET" => tables.hpet = parse_hpet(table),
+            b"FACP" => tables.fadt = Some(parse_fadt(table)),
+            _ => {}
+        }
+    }
+    tables
+}
+
+/// Reads a little-endian field, or returns 0 if the table is too short to have it.
+/// Newer revisions only ever add fields, and a missing field means the same as a zero one.
+fn field<T: Copy + Default>(bytes: &[u8], offset: usize) -> T {
+    match bytes.get(offset..offset + size_of::<T>()) {
+        Some(field) => unsafe { (field.as_ptr() as *const T).read_unaligned() },
+        None => T::default(),
+    }
+}
+
+/// Returns `length` bytes of physical memory.
+///
+/// # Safety
+///
+/// The range must be mapped in Limine's higher-half direct map, which ACPI memory always is.
+fn physical(address: u64, length: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts((boot::hhdm_offset() + address) as *const u8, length) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Returns the physical addresses of the tables the RSDT or XSDT points to.
+fn root_entries(rsdp: u64) -> Vec<u64> {
+    let header = physical(rsdp, 20);
+    return_if!(
+        &header[..8] != b"RSD PTR " || !checksum_ok(header),
+        Vec::new()
+    );
+    let revision: u8 = field(header, 15);
+    // ACPI 2.0 added the XSDT, whose entries are 64 bits wide.
+    let (root, entry_size) = match revision {
+        0 => (field::<u32>(header, 16) as u64, 4),
+        _ => (field::<u64>(physical(rsdp, 36), 24), 8),
+    };
+    let Some(root) = table(root) else {
+        return Vec::new();
+    };
+    root[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            4 => field::<u32>(entry, 0) as u64,
+            _ => field::<u64>(entry, 0),
+        })
+        .collect()
+}
+
+/// Returns the whole table at `address`, if its checksum adds up.
+fn table(address: u64) -> Option<&'static [u8]> {
+    return_if!(address == 0, None);
+    let length: u32 = field(physical(address, HEADER_SIZE), 4);
+    return_if!((length as usize) < HEADER_SIZE, None);
+    let table = physical(address, length as usize);
+    if !checksum_ok(table) {
+        info_println!(
+            "acpi: bad checksum in {}",
+            core::str::from_utf8(&table[..4]).unwrap_or("????")
+        );
+        return None;
+    }
+    Some(table)
+}
+
+fn parse_madt(table: &[u8]) -> Madt {
+    let mut madt = Madt {
+        local_apic_address: field::<u32>(table, 36) as u64,
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+    };
+    let mut offset = HEADER_SIZE + 8;
+    while let Some(&[kind, length]) = table.get(offset..offset + 2) {
+        let Some(entry) = table.get(offset..offset + length as usize) else {
+            break;
+        };
+        match kind {
+            0 => madt.local_apics.push(LocalApic {
+                processor_id: field(entry, 2),
+                apic_id: field(entry, 3),
+                enabled: field::<u32>(entry, 4) & 1 != 0,
+            }),
+            1 => madt.io_apics.push(IoApic {
+                id: field(entry, 2),
+                address: field(entry, 4),
+                interrupt_base: field(entry, 8),
+            }),
+            5 => madt.local_apic_address = field(entry, 4),
+            _ => {}
+        }
+        // A zero length would loop forever.
+        return_if!(length < 2, madt);
+        offset += length as usize;
+    }
+    madt
+}
+
+fn parse_hpet(table: &[u8]) -> Option<Hpet> {
+    // The registers are described by a generic address structure, which must be in memory space.
+    let address_space: u8 = field(table, 40);
+    return_if!(address_space != 0, None);
+    Some(Hpet {
+        address: field(table, 44),
+    })
+}
+
+/// Feature flag: the reset register is there to be used.
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+fn parse_fadt(table: &[u8]) -> Fadt {
+    let flags: u32 = field(table, 112);
+    let reset =
+        (flags & FADT_RESET_REG_SUP != 0).then(|| (generic_address(table, 116), field(table, 128)));
+    // ACPI 2.0 added a 64-bit pointer to the DSDT, which wins when it's set.
+    let dsdt = match field::<u64>(table, 140) {
+        0 => field::<u32>(table, 40) as u64,
+        dsdt => dsdt,
+    };
+    Fadt {
+        century: field(table, 108),
+        smi_command: field(table, 48),
+        acpi_enable: field(table, 52),
+        pm1a_control: field(table, 64),
+        pm1b_control: field(table, 68),
+        reset,
+        sleep_type_s5: self::table(dsdt).and_then(sleep_type_s5),
+    }
+}
+
+fn generic_address(table: &[u8], offset: usize) -> GenericAddress {
+    GenericAddress {
+        space: match field::<u8>(table, offset) {
+            0 => AddressSpace::Memory,
+            1 => AddressSpace::Io,
+            space => AddressSpace::Other(space),
+        },
+        address: field(table, offset + 4),
+    }
+}
+
+/// Finds the sleep type values for soft-off in the DSDT's AML.
+///
+/// # Safety
+///
+/// There's no AML interpreter, so this looks for the usual encoding of
+/// `Name (_S5_, Package () { SLP_TYPa, SLP_TYPb, ... })` and gives up on anything else.
+fn sleep_type_s5(dsdt: &[u8]) -> Option<(u16, u16)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+    let aml = &dsdt[HEADER_SIZE..];
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+    // The name is either right after NameOp or after a root prefix (`\_S5_`).
+    let before = aml[..position].last_chunk::<2>()?;
+    return_if!(
+        before[1] != NAME_OP && !(before[0] == NAME_OP && before[1] == b'\\'),
+        None
+    );
+    let mut rest = aml.get(position + 4..)?;
+    return_if!(rest.first() != Some(&PACKAGE_OP), None);
+    // The top two bits of the first PkgLength byte say how many more bytes it has.
+    let length_bytes = (*rest.get(1)? >> 6) as usize + 1;
+    // Then comes NumElements.
+    rest = rest.get(1 + length_bytes + 1..)?;
+    let mut value = || -> Option<u16> {
+        let (value, size) = match *rest.first()? {
+            ZERO_OP => (0, 1),
+            ONE_OP => (1, 1),
+            BYTE_PREFIX => (*rest.get(1)? as u16, 2),
+            _ => return None,
+        };
+        rest = &rest[size..];
+        Some(value)
+    };
+    Some((value()?, value()?))
+}
@@ -0,0 +1,60 @@
+use alloc::vec::Vec;
+use limine::request::{ExecutableCmdlineRequest, HhdmRequest, ModuleRequest, RsdpRequest};
+
+static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
+static CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
+
+pub struct BootModule {
+    pub path: &'static [u8],
+    pub string: &'static [u8],
+    pub data: &'static [u8],
+}
+
+/// Collects the modules Limine loaded alongside the kernel.
+///
+/// # Safety
+///
+/// Module memory is never reclaimed, so the slices live forever.
+pub fn modules() -> Vec<BootModule> {
+    let Some(response) = MODULE_REQUEST.get_response() else {
+        return Vec::new();
+    };
+    response
+        .modules()
+        .iter()
+        .map(|file| BootModule {
+            path: file.path(),
+            string: file.string(),
+            data: unsafe { core::slice::from_raw_parts(file.addr(), file.size() as usize) },
+        })
+        .collect()
+}
+
+/// Returns the kernel command line, or an empty string if there isn't one.
+pub fn cmdline() -> &'static str {
+    CMDLINE_REQUEST
+        .get_response()
+        .and_then(|response| response.cmdline().to_str().ok())
+        .unwrap_or("")
+}
+
+/// Returns where Limine's higher-half direct map of physical memory starts.
+pub fn hhdm_offset() -> u64 {
+    HHDM_REQUEST
+        .get_response()
+        .map_or(0, |response| response.offset())
+}
+
+/// Returns the physical address of the ACPI RSDP, if the firmware has one.
+pub fn rsdp() -> Option<u64> {
+    let address = RSDP_REQUEST.get_response()?.address() as u64;
+    // Older base revisions hand over a pointer into the direct map instead.
+    let offset = hhdm_offset();
+    Some(if address >= offset {
+        address - offset
+    } else {
+        address
+    })
+}
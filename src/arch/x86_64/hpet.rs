@@ -0,0 +1,79 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const HPET_CAPABILITIES: u64 = 0x00;
+const HPET_CONFIGURATION: u64 = 0x10;
+const HPET_MAIN_COUNTER: u64 = 0xF0;
+
+/// Capabilities: the main counter is 64 bits wide rather than 32.
+const COUNTER_64_BIT: u64 = 1 << 13;
+/// Configuration: the main counter is running.
+const ENABLE: u64 = 1;
+/// The spec caps the tick period at 100ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// Virtual address of the HPET's registers, or 0 if there's no HPET.
+static HPET_BASE: AtomicU64 = AtomicU64::new(0);
+/// How long one tick of the main counter is, in femtoseconds.
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+/// The bits of the main counter that count.
+static COUNTER_MASK: AtomicU64 = AtomicU64::new(0);
+
+fn hpet_read(register: u64) -> u64 {
+    unsafe {
+        core::ptr::read_volatile((HPET_BASE.load(Ordering::Relaxed) + register) as *const u64)
+    }
+}
+
+fn hpet_write(register: u64, value: u64) {
+    unsafe {
+        core::ptr::write_volatile(
+            (HPET_BASE.load(Ordering::Relaxed) + register) as *mut u64,
+            value,
+        )
+    }
+}
+
+/// Starts the HPET's main counter, if ACPI describes one.
+///
+/// # Safety
+///
+/// `acpi_init` must have run. The registers are reached through Limine's higher-half direct map.
+pub fn hpet_init() -> bool {
+    let Some(hpet) = crate::acpi::hpet() else {
+        return false;
+    };
+    HPET_BASE.store(crate::boot::hhdm_offset() + hpet.address, Ordering::Relaxed);
+    let capabilities = hpet_read(HPET_CAPABILITIES);
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        HPET_BASE.store(0, Ordering::Relaxed);
+        return false;
+    }
+    let mask = match capabilities & COUNTER_64_BIT {
+        0 => u32::MAX as u64,
+        _ => u64::MAX,
+    };
+    hpet_write(HPET_CONFIGURATION, hpet_read(HPET_CONFIGURATION) | ENABLE);
+    COUNTER_MASK.store(mask, Ordering::Relaxed);
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    true
+}
+
+/// Returns whether `hpet_init` found a working HPET.
+pub fn available() -> bool {
+    PERIOD_FS.load(Ordering::Relaxed) != 0
+}
+
+/// Busy-waits for `ms` milliseconds using the main counter.
+///
+/// # Safety
+///
+/// Only call this if `available` says so.
+pub fn hpet_sleep(ms: u64) {
+    let mask = COUNTER_MASK.load(Ordering::Relaxed);
+    let ticks = ms * 1_000_000_000_000 / PERIOD_FS.load(Ordering::Relaxed);
+    let start = hpet_read(HPET_MAIN_COUNTER);
+    while hpet_read(HPET_MAIN_COUNTER).wrapping_sub(start) & mask < ticks {
+        core::hint::spin_loop();
+    }
+}
@@ -1,3 +1,4 @@
+use super::timer::{self, SPURIOUS_VECTOR, TIMER_VECTOR};
 use spin::Mutex;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
@@ -36,6 +37,8 @@ pub fn irq_init() {
         idt.vmm_communication_exception
             .set_handler_fn(vmm_communication_exception);
         idt.x87_floating_point.set_handler_fn(x87_floating_point);
+        idt[TIMER_VECTOR].set_handler_fn(timer_interrupt);
+        idt[SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt);
         idt.load_unsafe();
     }
 }
@@ -134,3 +137,10 @@ extern "x86-interrupt" fn vmm_communication_exception(
 extern "x86-interrupt" fn x87_floating_point(_stack: InterruptStackFrame) {
     panic!("Unhandled interrupt");
 }
+
+extern "x86-interrupt" fn timer_interrupt(_stack: InterruptStackFrame) {
+    timer::timer_tick();
+    crate::sched::preempt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt(_stack: InterruptStackFrame) {}
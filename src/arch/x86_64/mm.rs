@@ -1,6 +1,8 @@
+use core::alloc::{GlobalAlloc, Layout};
 use limine::memory_map::EntryType;
 use linked_list_allocator::LockedHeap;
 use x86::controlregs::cr3;
+use x86_64::instructions::interrupts::without_interrupts;
 
 use crate::return_if;
 
@@ -10,7 +12,21 @@ static MEMMAP_REQUEST: limine::request::MemoryMapRequest = limine::request::Memo
 const HEAP_START: u64 = 320u64 << 39;
 //const HEAP_DEFAULT_SIZE: usize = 4096usize * 1024;
 #[global_allocator]
-static HEAP: LockedHeap = LockedHeap::empty();
+static HEAP: Heap = Heap(LockedHeap::empty());
+
+/// The kernel heap, which keeps interrupts disabled while it's locked
+/// so that a preempted thread can never be holding it.
+struct Heap(LockedHeap);
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| unsafe { self.0.alloc(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| unsafe { self.0.dealloc(ptr, layout) })
+    }
+}
 
 /// Initializes the freelist.
 ///
@@ -25,8 +41,12 @@ pub fn mm_init() {
         }
         free_region(entry.base, entry.length);
     }
+    let heap_limit = crate::cmdline::get().heap_size.map(|size| size as u64 / 4096);
     let mut heap_page = 0;
     loop {
+        if heap_limit.is_some_and(|limit| heap_page >= limit) {
+            break;
+        }
         unsafe {
             let page = unlink_page::<u8>();
             if page.is_null() {
@@ -37,7 +57,7 @@ pub fn mm_init() {
         heap_page += 1;
     }
     unsafe {
-        HEAP.lock().init(
+        HEAP.0.lock().init(
             ((0xffffu64 << 48) + HEAP_START) as *mut u8,
             (heap_page * 4096) as usize,
         )
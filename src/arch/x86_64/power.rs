@@ -0,0 +1,81 @@
+use crate::acpi::{self, AddressSpace, Fadt};
+use crate::return_if;
+use x86::io::{inb, inw, outb, outl, outw};
+
+/// QEMU's `isa-debug-exit` device, when it's plugged in at the usual port.
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+const KEYBOARD_COMMAND: u16 = 0x64;
+/// Keyboard controller status: it hasn't read the last command yet.
+const KEYBOARD_BUSY: u8 = 1 << 1;
+/// Keyboard controller command: pulse the CPU reset line.
+const KEYBOARD_RESET: u8 = 0xFE;
+/// PM1 control: the machine is in ACPI mode.
+const SCI_EN: u16 = 1;
+/// PM1 control: enter the sleep state in SLP_TYP.
+const SLP_EN: u16 = 1 << 13;
+
+/// Powers off through QEMU's `isa-debug-exit` device if there is one, and ACPI soft-off otherwise.
+///
+/// # Safety
+///
+/// With `-device isa-debug-exit,iobase=0xf4,iosize=0x04`, QEMU exits with status `code << 1 | 1`.
+/// Without it the write goes nowhere. Halts forever if the DSDT has no `_S5_` the kernel can read.
+pub fn shutdown(code: i32) -> ! {
+    unsafe {
+        x86::irq::disable();
+        outl(DEBUG_EXIT_PORT, code as u32);
+    }
+    if let Some(fadt) = acpi::fadt()
+        && let Some((sleep_type_a, sleep_type_b)) = fadt.sleep_type_s5
+        && fadt.pm1a_control != 0
+    {
+        enable_acpi(fadt);
+        unsafe {
+            outw(fadt.pm1a_control as u16, sleep_type_a << 10 | SLP_EN);
+            if fadt.pm1b_control != 0 {
+                outw(fadt.pm1b_control as u16, sleep_type_b << 10 | SLP_EN);
+            }
+        }
+    }
+    crate::hcf()
+}
+
+/// Takes the machine out of legacy mode, which firmware may leave it in.
+fn enable_acpi(fadt: &Fadt) {
+    let enabled = || unsafe { inw(fadt.pm1a_control as u16) } & SCI_EN != 0;
+    return_if!(enabled() || fadt.smi_command == 0 || fadt.acpi_enable == 0);
+    unsafe { outb(fadt.smi_command as u16, fadt.acpi_enable) };
+    // Firmware can take a while to hand over. Give it three seconds.
+    for _ in 0..300 {
+        return_if!(enabled());
+        crate::time::arch::sleep(10);
+    }
+}
+
+/// Resets through the FADT's reset register, and the keyboard controller if that doesn't work.
+///
+/// # Safety
+///
+/// Halts forever if neither does.
+pub fn reboot() -> ! {
+    unsafe { x86::irq::disable() };
+    if let Some((register, value)) = acpi::fadt().and_then(|fadt| fadt.reset) {
+        match register.space {
+            AddressSpace::Io => unsafe { outb(register.address as u16, value) },
+            AddressSpace::Memory => unsafe {
+                let address = crate::boot::hhdm_offset() + register.address;
+                core::ptr::write_volatile(address as *mut u8, value);
+            },
+            AddressSpace::Other(_) => {}
+        }
+    }
+    unsafe {
+        for _ in 0..0x10000 {
+            if inb(KEYBOARD_COMMAND) & KEYBOARD_BUSY == 0 {
+                break;
+            }
+        }
+        outb(KEYBOARD_COMMAND, KEYBOARD_RESET);
+    }
+    crate::hcf()
+}
@@ -0,0 +1,126 @@
+use crate::return_if;
+use x86::io::{inb, outb};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REGISTER_SECOND: u8 = 0x00;
+const REGISTER_MINUTE: u8 = 0x02;
+const REGISTER_HOUR: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+
+/// Status A: the clock is about to change its registers.
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Status B: the hour counts 0 to 23 rather than 1 to 12.
+const HOURS_24: u8 = 0x02;
+/// Status B: the fields are plain binary rather than BCD.
+const BINARY: u8 = 0x04;
+/// In 12-hour mode, the top bit of the hour means PM.
+const PM: u8 = 0x80;
+
+#[derive(PartialEq, Eq)]
+struct Reading {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn cmos_read(register: u8) -> u8 {
+    unsafe {
+        // Bit 7 of the address port masks NMIs, so keep it clear.
+        outb(CMOS_ADDRESS, register & 0x7F);
+        inb(CMOS_DATA)
+    }
+}
+
+/// Reads the time fields once an update isn't about to happen.
+fn read_fields(century_register: u8) -> Reading {
+    while cmos_read(REGISTER_STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    Reading {
+        second: cmos_read(REGISTER_SECOND),
+        minute: cmos_read(REGISTER_MINUTE),
+        hour: cmos_read(REGISTER_HOUR),
+        day: cmos_read(REGISTER_DAY),
+        month: cmos_read(REGISTER_MONTH),
+        year: cmos_read(REGISTER_YEAR),
+        century: match century_register {
+            0 => 0,
+            register => cmos_read(register),
+        },
+    }
+}
+
+/// Decodes a BCD field, or `None` if either digit is past 9.
+fn from_bcd(value: u8) -> Option<u8> {
+    let (tens, ones) = (value >> 4, value & 0xF);
+    (tens <= 9 && ones <= 9).then_some(tens * 10 + ones)
+}
+
+/// Reads the CMOS real-time clock as seconds since the Unix epoch,
+/// or `None` if a field is out of range or the date is before 1970.
+///
+/// # Safety
+///
+/// An update can still start between the status check and the last field, so the fields are read
+/// until two readings in a row agree. The clock is assumed to keep UTC, and to be in the
+/// 21st century unless the FADT says where the century register is.
+pub fn read() -> Option<u64> {
+    let century_register = crate::acpi::fadt().map_or(0, |fadt| fadt.century);
+    let mut reading = read_fields(century_register);
+    loop {
+        let again = read_fields(century_register);
+        if again == reading {
+            break;
+        }
+        reading = again;
+    }
+    let status = cmos_read(REGISTER_STATUS_B);
+    let decode = |value: u8| -> Option<u64> {
+        match status & BINARY {
+            0 => from_bcd(value).map(u64::from),
+            _ => Some(value as u64),
+        }
+    };
+    let mut hour = decode(reading.hour & !PM)?;
+    if status & HOURS_24 == 0 {
+        return_if!(!(1..=12).contains(&hour), None);
+        // 12 AM is midnight and 12 PM is noon.
+        hour %= 12;
+        if reading.hour & PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = match century_register {
+        0 => 20,
+        _ => decode(reading.century)?,
+    };
+    let year = century * 100 + decode(reading.year).filter(|year| *year < 100)?;
+    let month = decode(reading.month).filter(|month| (1..=12).contains(month))?;
+    let day = decode(reading.day).filter(|day| (1..=31).contains(day))?;
+    let minute = decode(reading.minute).filter(|minute| *minute < 60)?;
+    let second = decode(reading.second).filter(|second| *second < 60)?;
+    return_if!(hour >= 24 || year < 1970, None);
+    let days = days_from_civil(year, month, day);
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Counts the days from 1970-01-01 to a date, using Howard Hinnant's `days_from_civil`.
+/// `read` has checked the date is a real one from 1970 on, so nothing here underflows.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
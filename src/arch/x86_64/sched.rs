@@ -0,0 +1,95 @@
+use alloc::boxed::Box;
+use core::arch::{asm, naked_asm};
+
+pub use x86_64::instructions::interrupts::{enable as enable_interrupts, without_interrupts};
+
+/// Where `fxsave` puts the x87 and SSE registers, which wasm3 uses.
+#[repr(C, align(16))]
+struct FpuState([u8; 512]);
+
+/// Saved state of a thread that isn't running: its stack pointer,
+/// since `switch` pushes the callee-saved registers onto the stack,
+/// and its floating point registers, since a preempted thread may be using them.
+pub struct Context {
+    rsp: u64,
+    fpu: Box<FpuState>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            rsp: 0,
+            fpu: Box::new(FpuState([0; 512])),
+        }
+    }
+}
+
+impl Context {
+    /// Sets up a fresh stack so that switching to it calls `entry`.
+    ///
+    /// # Safety
+    ///
+    /// The stack must stay allocated for as long as the thread exists.
+    pub fn new(stack: &mut [u8], entry: extern "C" fn() -> !) -> Self {
+        // A new thread starts out with whatever floating point state was live,
+        // since its own is only restored when it's switched back to.
+        let mut context = Self::default();
+        context.restart(stack, entry);
+        context
+    }
+
+    /// Throws away whatever the thread was doing, so that switching to it calls `entry` on a fresh stack.
+    ///
+    /// # Safety
+    ///
+    /// Nothing on the old stack is dropped. Doesn't allocate, so the scheduler can call it.
+    pub fn restart(&mut self, stack: &mut [u8], entry: extern "C" fn() -> !) {
+        let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xF;
+        unsafe {
+            // Padding where `entry`'s return address would be, so it starts with a call-aligned stack.
+            *((top - 8) as *mut u64) = 0;
+            *((top - 16) as *mut u64) = entry as usize as u64;
+            // rbp, rbx, r12, r13, r14 and r15 all start out zeroed.
+            for slot in 3..=8 {
+                *((top - 8 * slot) as *mut u64) = 0;
+            }
+        }
+        self.rsp = top - 64;
+    }
+}
+
+/// Saves the current thread's registers into `from` and resumes the one saved in `to`.
+///
+/// # Safety
+///
+/// `to` must come from `Context::new` or an earlier `switch`, no locks may be held,
+/// and interrupts must be disabled.
+pub fn switch(from: *mut Context, to: *const Context) {
+    unsafe {
+        asm!("fxsave64 [{}]", in(reg) (*from).fpu.0.as_mut_ptr());
+        switch_stacks(&raw mut (*from).rsp, &raw const (*to).rsp);
+        // Back on the stack of `from`, whose registers are ours again.
+        asm!("fxrstor64 [{}]", in(reg) (*from).fpu.0.as_ptr());
+    }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn switch_stacks(_from: *mut u64, _to: *const u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, [rsi]",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
@@ -1,43 +1,57 @@
+use core::sync::atomic::{AtomicU16, Ordering};
 use x86::io::{inb, outb};
 
-const COM1: u16 = 0x3f8;
+static PORT: AtomicU16 = AtomicU16::new(0x3f8);
 
-/// Initializes the COM1 serial port.
+/// Initializes the serial port chosen on the command line (COM1 by default).
 /// Silently returns if the port doesn't work.
 ///
 /// # Safety
 ///
 /// Uses port I/O but shouldn't cause problems.
 pub fn serial_init() {
+    let port = crate::cmdline::get().serial_port;
+    PORT.store(port, Ordering::Relaxed);
     unsafe {
-        outb(COM1 + 1, 0);
-        outb(COM1 + 3, 0x80);
-        outb(COM1, 0x03);
-        outb(COM1 + 1, 0);
-        outb(COM1 + 3, 0x03);
-        outb(COM1 + 2, 0xC7);
-        outb(COM1 + 4, 0x03);
-        outb(COM1 + 4, 0x1E);
-        outb(COM1, 0xAE);
-        if inb(COM1) != 0xAE {
+        outb(port + 1, 0);
+        outb(port + 3, 0x80);
+        outb(port, 0x03);
+        outb(port + 1, 0);
+        outb(port + 3, 0x03);
+        outb(port + 2, 0xC7);
+        outb(port + 4, 0x03);
+        outb(port + 4, 0x1E);
+        outb(port, 0xAE);
+        if inb(port) != 0xAE {
             return;
         }
-        outb(COM1 + 4, 0x03);
+        outb(port + 4, 0x03);
     }
 }
 
-/// Sends a byte to COM1.
+/// Sends a byte to the serial port.
 ///
 /// # Safety
 ///
 /// Uses port I/O but shouldn't cause problems.
 pub fn serial_send(byte: u8) {
+    let port = PORT.load(Ordering::Relaxed);
     unsafe {
-        while inb(COM1 + 5) & 0x20 == 0 {}
-        outb(COM1, byte);
+        while inb(port + 5) & 0x20 == 0 {}
+        outb(port, byte);
     }
 }
 
+/// Receives a byte from the serial port, if one has arrived.
+///
+/// # Safety
+///
+/// Uses port I/O but shouldn't cause problems.
+pub fn serial_recv() -> Option<u8> {
+    let port = PORT.load(Ordering::Relaxed);
+    unsafe { (inb(port + 5) & 1 != 0).then(|| inb(port)) }
+}
+
 use core::fmt;
 use spin::Mutex;
 
@@ -72,12 +86,23 @@ macro_rules! println {
     ($($t:tt)*) => { $crate::print!("{}\n", format_args!($($t)*)) };
 }
 
+#[macro_export]
+macro_rules! info_println {
+    ($($t:tt)*) => {
+        if $crate::cmdline::get().log_level >= $crate::cmdline::LogLevel::Info {
+            $crate::println!($($t)*);
+        }
+    };
+}
+
 #[cfg(debug_assertions)]
 #[macro_export]
 macro_rules! debug_println {
-    ()          => { $crate::print!("\n"); };
-    // On nightly, `format_args_nl!` could also be used.
-    ($($t:tt)*) => { $crate::print!("{}\n", format_args!($($t)*)) };
+    ($($t:tt)*) => {
+        if $crate::cmdline::get().log_level >= $crate::cmdline::LogLevel::Debug {
+            $crate::println!($($t)*);
+        }
+    };
 }
 
 #[cfg(not(debug_assertions))]
@@ -0,0 +1,61 @@
+use crate::irq::timer::{TIMER_HZ, pit_sleep, ticks};
+use crate::time::hpet;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86::cpuid::CpuId;
+use x86::time::rdtsc;
+
+/// How long the TSC is measured for. PIT channel 2 can't count much past 54ms.
+const CALIBRATION_MS: u64 = 50;
+
+/// TSC ticks per second, or 0 if there's no TSC and time comes from the timer interrupt.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// The TSC reading that counts as time zero.
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+/// Measures how fast the TSC runs against the HPET, or the PIT if there's no HPET.
+///
+/// # Safety
+///
+/// Interrupts must be off, or one landing during the measurement skews it.
+/// Assumes the TSC rate doesn't change afterwards, which holds on anything with an invariant TSC and on QEMU.
+pub fn time_init() {
+    hpet::hpet_init();
+    let has_tsc = CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_tsc());
+    if !has_tsc {
+        return;
+    }
+    let start = unsafe { rdtsc() };
+    sleep(CALIBRATION_MS);
+    let end = unsafe { rdtsc() };
+    TSC_START.store(start, Ordering::Relaxed);
+    TSC_HZ.store((end - start) * 1000 / CALIBRATION_MS, Ordering::Relaxed);
+}
+
+/// Busy-waits for `ms` milliseconds on the most precise timer there is.
+pub fn sleep(ms: u64) {
+    if hpet::available() {
+        hpet::hpet_sleep(ms);
+    } else {
+        pit_sleep(ms);
+    }
+}
+
+/// Returns nanoseconds since `time_init`.
+pub fn nanoseconds() -> u64 {
+    let hz = TSC_HZ.load(Ordering::Relaxed);
+    if hz == 0 {
+        return ticks() * (1_000_000_000 / TIMER_HZ);
+    }
+    let elapsed = unsafe { rdtsc() } - TSC_START.load(Ordering::Relaxed);
+    (elapsed as u128 * 1_000_000_000 / hz as u128) as u64
+}
+
+/// Returns how many nanoseconds apart two different readings of `nanoseconds` are at least.
+pub fn resolution() -> u64 {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => 1_000_000_000 / TIMER_HZ,
+        hz => (1_000_000_000 / hz).max(1),
+    }
+}
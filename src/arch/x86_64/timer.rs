@@ -0,0 +1,139 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86::cpuid::CpuId;
+use x86::io::{inb, outb};
+use x86::msr::{IA32_APIC_BASE, rdmsr, wrmsr};
+
+/// How many times per second the timer interrupts.
+pub const TIMER_HZ: u64 = 100;
+pub const TIMER_VECTOR: u8 = 32;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const PIT_FREQUENCY: u64 = 1193182;
+const APIC_EOI: u64 = 0xB0;
+const APIC_SPURIOUS: u64 = 0xF0;
+const APIC_LVT_TIMER: u64 = 0x320;
+const APIC_INITIAL_COUNT: u64 = 0x380;
+const APIC_CURRENT_COUNT: u64 = 0x390;
+const APIC_DIVIDE: u64 = 0x3E0;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static APIC_BASE: AtomicU64 = AtomicU64::new(0);
+static USING_APIC: AtomicBool = AtomicBool::new(false);
+
+fn apic_read(register: u64) -> u32 {
+    unsafe {
+        core::ptr::read_volatile((APIC_BASE.load(Ordering::Relaxed) + register) as *const u32)
+    }
+}
+
+fn apic_write(register: u64, value: u32) {
+    unsafe {
+        core::ptr::write_volatile(
+            (APIC_BASE.load(Ordering::Relaxed) + register) as *mut u32,
+            value,
+        )
+    }
+}
+
+/// Starts periodic timer interrupts, using the Local APIC timer if there is one and the PIT otherwise.
+///
+/// # Safety
+///
+/// The timer vector must already have a handler in the IDT. Enables interrupts.
+pub fn timer_init() {
+    pic_init();
+    let has_apic = CpuId::new()
+        .get_feature_info()
+        .is_some_and(|info| info.has_apic());
+    if has_apic {
+        apic_init();
+    } else {
+        pit_init();
+    }
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Moves the legacy PIC out of the way of the CPU exceptions and masks everything.
+fn pic_init() {
+    unsafe {
+        outb(0x20, 0x11);
+        outb(0xA0, 0x11);
+        outb(0x21, 0x20);
+        outb(0xA1, 0x28);
+        outb(0x21, 4);
+        outb(0xA1, 2);
+        outb(0x21, 1);
+        outb(0xA1, 1);
+        outb(0x21, 0xFF);
+        outb(0xA1, 0xFF);
+    }
+}
+
+/// Programs the Local APIC timer for `TIMER_HZ` ticks, calibrated against the HPET or PIT.
+///
+/// # Safety
+///
+/// The APIC's registers are reached through Limine's identity map of the low 4GiB.
+/// Their address comes from the MADT, or the APIC base MSR if ACPI doesn't say.
+fn apic_init() {
+    unsafe {
+        let base = rdmsr(IA32_APIC_BASE);
+        wrmsr(IA32_APIC_BASE, base | 1 << 11);
+        let address =
+            crate::acpi::madt().map_or(base & 0xFFFF_F000, |madt| madt.local_apic_address);
+        APIC_BASE.store(address, Ordering::Relaxed);
+    }
+    apic_write(APIC_SPURIOUS, 0x100 | SPURIOUS_VECTOR as u32);
+    apic_write(APIC_DIVIDE, 0x3);
+    apic_write(APIC_INITIAL_COUNT, u32::MAX);
+    crate::time::arch::sleep(10);
+    let counts_per_ms = (u32::MAX - apic_read(APIC_CURRENT_COUNT)) as u64 / 10;
+    apic_write(APIC_LVT_TIMER, 1 << 17 | TIMER_VECTOR as u32);
+    apic_write(APIC_INITIAL_COUNT, (counts_per_ms * 1000 / TIMER_HZ) as u32);
+    USING_APIC.store(true, Ordering::Relaxed);
+}
+
+/// Programs PIT channel 0 for `TIMER_HZ` ticks and unmasks IRQ 0.
+fn pit_init() {
+    let divisor = PIT_FREQUENCY / TIMER_HZ;
+    unsafe {
+        outb(0x43, 0x36);
+        outb(0x40, divisor as u8);
+        outb(0x40, (divisor >> 8) as u8);
+        outb(0x21, 0xFE);
+    }
+}
+
+/// Busy-waits for `ms` milliseconds using PIT channel 2.
+///
+/// # Safety
+///
+/// Turns off the PC speaker, if anything had it on.
+pub fn pit_sleep(ms: u64) {
+    let count = PIT_FREQUENCY * ms / 1000;
+    unsafe {
+        outb(0x61, (inb(0x61) & 0xFD) | 1);
+        outb(0x43, 0xB0);
+        outb(0x42, count as u8);
+        outb(0x42, (count >> 8) as u8);
+        let gate = inb(0x61) & 0xFE;
+        outb(0x61, gate);
+        outb(0x61, gate | 1);
+        while inb(0x61) & 0x20 == 0 {}
+    }
+}
+
+/// Counts a tick and acknowledges the interrupt, so the next one can arrive.
+pub fn timer_tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    if USING_APIC.load(Ordering::Relaxed) {
+        apic_write(APIC_EOI, 0);
+    } else {
+        unsafe { outb(0x20, 0x20) };
+    }
+}
+
+/// Returns how many ticks there have been since the timer started.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
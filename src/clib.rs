@@ -1,5 +1,8 @@
-use crate::fs::OpenFlags;
-use crate::{debug_println, print, return_if};
+use crate::errno::{Errno, Result};
+use crate::fs::{Descriptor, FileType, Metadata, OpenFlags};
+use crate::process::Pid;
+use crate::sched::KernelSection;
+use crate::{debug_println, print};
 use alloc::string::String;
 use alloc::{collections::BTreeMap, string::ToString};
 use core::alloc::Layout;
@@ -12,27 +15,61 @@ pub struct MutPtr<T>(*mut T);
 unsafe impl<T> Send for MutPtr<T> {}
 unsafe impl<T> Sync for MutPtr<T> {}
 
-static C_ALLOCATIONS: Mutex<BTreeMap<MutPtr<u8>, Layout>> = Mutex::new(BTreeMap::new());
+/// A block handed out by `malloc`, and the process it counts against.
+#[derive(Clone, Copy)]
+struct Allocation {
+    layout: Layout,
+    pid: Option<Pid>,
+}
+
+static C_ALLOCATIONS: Mutex<BTreeMap<MutPtr<u8>, Allocation>> = Mutex::new(BTreeMap::new());
+
+/// Allocates for the current process, or returns null with `errno` set if it's over its limit.
+///
+/// # Safety
+///
+/// Callers must be in a `KernelSection`: wasm3 allocates while running guest code,
+/// and a thread killed holding `C_ALLOCATIONS` would leave it locked.
+fn allocate(size: usize, zeroed: bool) -> *mut u8 {
+    let pid = crate::sched::current_process();
+    if !crate::process::reserve_memory(pid, size) {
+        set_errno(Errno::NoMem);
+        return core::ptr::null_mut();
+    }
+    let layout = Layout::from_size_align(size, 1).unwrap();
+    let allocation = unsafe {
+        if zeroed {
+            alloc::alloc::alloc_zeroed(layout)
+        } else {
+            alloc::alloc::alloc(layout)
+        }
+    };
+    if allocation.is_null() {
+        crate::process::release_memory(pid, size);
+        set_errno(Errno::NoMem);
+        return allocation;
+    }
+    C_ALLOCATIONS
+        .lock()
+        .insert(MutPtr(allocation), Allocation { layout, pid });
+    allocation
+}
 
 #[unsafe(no_mangle)]
 pub extern "C" fn malloc(size: usize) -> *mut u8 {
-    let mut c_allocations = C_ALLOCATIONS.lock();
-    unsafe {
-        let layout = Layout::from_size_align(size, 1).unwrap();
-        let allocation = alloc::alloc::alloc(layout);
-        c_allocations.insert(MutPtr(allocation), layout);
-        allocation
-    }
+    let _kernel = KernelSection::enter();
+    allocate(size, false)
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn calloc(items: usize, size: usize) -> *mut u8 {
-    let mut c_allocations = C_ALLOCATIONS.lock();
-    unsafe {
-        let layout = Layout::from_size_align(items * size, 1).unwrap();
-        let allocation = alloc::alloc::alloc_zeroed(layout);
-        c_allocations.insert(MutPtr(allocation), layout);
-        allocation
+    let _kernel = KernelSection::enter();
+    match items.checked_mul(size) {
+        Some(size) => allocate(size, true),
+        None => {
+            set_errno(Errno::NoMem);
+            core::ptr::null_mut()
+        }
     }
 }
 
@@ -41,23 +78,42 @@ pub extern "C" fn free(ptr: *mut u8) {
     if ptr.is_null() {
         return;
     }
-    let c_allocations = C_ALLOCATIONS.lock();
-    unsafe { alloc::alloc::dealloc(ptr, *c_allocations.get(&MutPtr(ptr)).unwrap()) }
+    let _kernel = KernelSection::enter();
+    let allocation = C_ALLOCATIONS.lock().remove(&MutPtr(ptr)).unwrap();
+    unsafe { alloc::alloc::dealloc(ptr, allocation.layout) }
+    crate::process::release_memory(allocation.pid, allocation.layout.size());
 }
 
+/// Resizes a block, leaving it alone and returning null if the process can't have the difference.
 #[unsafe(no_mangle)]
 pub extern "C" fn realloc(ptr: *mut u8, size: usize) -> *mut u8 {
-    let mut c_allocations = C_ALLOCATIONS.lock();
-    unsafe {
-        let layout = Layout::from_size_align(size, 1).unwrap();
-        let allocation = if ptr.is_null() {
-            alloc::alloc::alloc_zeroed(layout)
-        } else {
-            alloc::alloc::realloc(ptr, *c_allocations.get(&MutPtr(ptr)).unwrap(), size)
-        };
-        c_allocations.insert(MutPtr(allocation), layout);
-        allocation
+    if ptr.is_null() {
+        return calloc(1, size);
+    }
+    let _kernel = KernelSection::enter();
+    let old = *C_ALLOCATIONS.lock().get(&MutPtr(ptr)).unwrap();
+    let growth = size.saturating_sub(old.layout.size());
+    if !crate::process::reserve_memory(old.pid, growth) {
+        set_errno(Errno::NoMem);
+        return core::ptr::null_mut();
+    }
+    let allocation = unsafe { alloc::alloc::realloc(ptr, old.layout, size) };
+    if allocation.is_null() {
+        crate::process::release_memory(old.pid, growth);
+        set_errno(Errno::NoMem);
+        return allocation;
     }
+    crate::process::release_memory(old.pid, old.layout.size().saturating_sub(size));
+    let mut c_allocations = C_ALLOCATIONS.lock();
+    c_allocations.remove(&MutPtr(ptr));
+    c_allocations.insert(
+        MutPtr(allocation),
+        Allocation {
+            layout: Layout::from_size_align(size, 1).unwrap(),
+            pid: old.pid,
+        },
+    );
+    allocation
 }
 
 #[unsafe(no_mangle)]
@@ -157,50 +213,221 @@ fn cstr_len(ptr: *const u8) -> usize {
     index
 }
 
+fn cstr_string(ptr: *const u8) -> String {
+    String::from_utf8_lossy(unsafe { core::slice::from_raw_parts(ptr, cstr_len(ptr)) }).to_string()
+}
+
+fn set_errno(errno: Errno) {
+    unsafe { ERRNO = errno.posix() };
+}
+
+/// Reports a failure the way libc does: -1, with the reason in `errno`.
+fn fail<T: From<i8>>(errno: Errno) -> T {
+    set_errno(errno);
+    T::from(-1)
+}
+
+/// Turns a result with nothing in it into 0, or -1 with `errno` set.
+fn check(result: Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(errno) => fail(errno),
+    }
+}
+
+const O_WRONLY: i32 = 0o1;
+const O_RDWR: i32 = 0o2;
+const O_ACCMODE: i32 = 0o3;
+const O_CREAT: i32 = 0o100;
+const O_EXCL: i32 = 0o200;
+const O_TRUNC: i32 = 0o1000;
+const O_APPEND: i32 = 0o2000;
+const O_DIRECTORY: i32 = 0o200000;
+
+/// Opens a file and gives it the current process's lowest free fd.
+/// `O_NOFOLLOW` is accepted, but there are no symbolic links for it to refuse.
+fn open_path(name: String, flags: i32) -> i32 {
+    let access = flags & O_ACCMODE;
+    let open_flags = OpenFlags {
+        read: access != O_WRONLY,
+        write: access == O_WRONLY || access == O_RDWR,
+        append: flags & O_APPEND != 0,
+        create: flags & O_CREAT != 0,
+        exclude: flags & O_EXCL != 0,
+        truncate: flags & O_TRUNC != 0,
+        directory: flags & O_DIRECTORY != 0,
+    };
+    let descriptor = match crate::fs::open(name, open_flags) {
+        Ok(descriptor) => descriptor,
+        Err(errno) => return fail(errno),
+    };
+    match crate::process::with_current(|process| process.fds.insert(descriptor)) {
+        Some(Ok(fd)) => fd,
+        Some(Err(errno)) => fail(errno),
+        None => fail(Errno::BadF),
+    }
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn open(pathname: *const u8, flags: i32, _mode: i32) -> i32 {
+    let _kernel = KernelSection::enter();
     debug_println!("(open)");
-    let name = String::from_utf8_lossy(unsafe {
-        core::slice::from_raw_parts(pathname, cstr_len(pathname))
-    })
-    .to_string();
-    let open_flags = OpenFlags {
-        append: flags & 0o2000 != 0,
-        exclude: flags & 0o200 != 0,
-        truncate: flags & 0o1000 != 0,
-    };
-    crate::fs::open(name, open_flags) as i32
+    open_path(cstr_string(pathname), flags)
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn close(file_descriptor: i32) -> i32 {
+    let _kernel = KernelSection::enter();
     debug_println!("(close)");
-    crate::fs::close(file_descriptor as isize);
-    0
+    // Dropped outside the process table's lock, since closing the last descriptor to a file
+    // can drop its inode, and releasing the memory its data was charged locks the table again.
+    let descriptor = crate::process::with_current(|process| process.fds.remove(file_descriptor));
+    match descriptor.flatten() {
+        Some(_) => 0,
+        None => fail(Errno::BadF),
+    }
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn fcntl(_fd: i32, _cmd: i32, _arg: i32) -> i32 {
+extern "C" fn fcntl(fd: i32, cmd: i32, arg: i32) -> i32 {
+    let _kernel = KernelSection::enter();
     debug_println!("(fcntl)");
-    0
+    match cmd {
+        // F_DUPFD and F_DUPFD_CLOEXEC
+        0 | 1030 => match crate::process::with_current(|process| process.fds.dup(fd, arg)) {
+            Some(Ok(new_fd)) => new_fd,
+            Some(Err(errno)) => fail(errno),
+            None => fail(Errno::BadF),
+        },
+        _ => 0,
+    }
+}
+
+/// musl's `struct stat` on x86_64.
+#[repr(C)]
+#[derive(Default)]
+struct Stat {
+    dev: u64,
+    ino: u64,
+    nlink: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    _pad: u32,
+    rdev: u64,
+    size: i64,
+    blksize: i64,
+    blocks: i64,
+    atim: [i64; 2],
+    mtim: [i64; 2],
+    ctim: [i64; 2],
+    _unused: [i64; 3],
+}
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Splits nanoseconds into a `struct timespec`.
+fn timespec(nanoseconds: u64) -> [i64; 2] {
+    [
+        (nanoseconds / 1_000_000_000) as i64,
+        (nanoseconds % 1_000_000_000) as i64,
+    ]
+}
+
+impl From<Metadata> for Stat {
+    fn from(metadata: Metadata) -> Self {
+        Stat {
+            ino: metadata.id,
+            nlink: metadata.links,
+            mode: match metadata.file_type {
+                FileType::File => S_IFREG | 0o644,
+                FileType::Directory => S_IFDIR | 0o755,
+            },
+            size: metadata.size as i64,
+            blksize: 4096,
+            blocks: metadata.size.div_ceil(512) as i64,
+            atim: timespec(metadata.times.accessed),
+            mtim: timespec(metadata.times.modified),
+            ctim: timespec(metadata.times.changed),
+            ..Default::default()
+        }
+    }
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn fstat(_fd: i32, _buf: *mut u8) -> i32 {
+extern "C" fn fstat(fd: i32, buf: *mut u8) -> i32 {
+    let _kernel = KernelSection::enter();
     debug_println!("(fstat)");
+    let stat = match crate::process::descriptor(fd) {
+        None => return fail(Errno::BadF),
+        Some(Descriptor::Console) => Stat {
+            nlink: 1,
+            mode: S_IFCHR | 0o620,
+            blksize: 1024,
+            ..Default::default()
+        },
+        Some(Descriptor::File(file)) => Stat::from(crate::fs::metadata(&file)),
+    };
+    unsafe { buf.cast::<Stat>().write_unaligned(stat) };
     0
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn lseek(fd: i32, offset: i64, whence: i32) -> i64 {
+    let _kernel = KernelSection::enter();
     debug_println!("(lseek)");
-    crate::fs::seek(fd as isize, offset as isize, whence) as i64
+    match crate::process::descriptor(fd) {
+        None => fail(Errno::BadF),
+        Some(Descriptor::Console) => fail(Errno::SPipe),
+        Some(Descriptor::File(file)) => match crate::fs::seek(&file, offset as isize, whence) {
+            Ok(position) => position as i64,
+            Err(errno) => fail(errno),
+        },
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn unlink(pathname: *const u8) -> i32 {
+    let _kernel = KernelSection::enter();
+    debug_println!("(unlink)");
+    check(crate::fs::unlink(&cstr_string(pathname)))
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn rename(oldpath: *const u8, newpath: *const u8) -> i32 {
+    let _kernel = KernelSection::enter();
+    debug_println!("(rename)");
+    check(crate::fs::rename(&cstr_string(oldpath), &cstr_string(newpath)))
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn openat(_dirfd: i32, _pathname: *const u8, _flags: i32, _mode: i32) -> i32 {
+extern "C" fn ftruncate(fd: i32, length: i64) -> i32 {
+    let _kernel = KernelSection::enter();
+    debug_println!("(ftruncate)");
+    match crate::process::descriptor(fd) {
+        None | Some(Descriptor::Console) => fail(Errno::BadF),
+        Some(Descriptor::File(file)) => match usize::try_from(length) {
+            Ok(length) => check(crate::fs::truncate(&file, length)),
+            Err(_) => fail(Errno::Inval),
+        },
+    }
+}
+
+/// Opens a path relative to the directory at `dirfd`, which it can't climb out of.
+#[unsafe(no_mangle)]
+extern "C" fn openat(dirfd: i32, pathname: *const u8, flags: i32, _mode: i32) -> i32 {
+    let _kernel = KernelSection::enter();
     debug_println!("(openat)");
-    -1
+    let Some(Descriptor::File(directory)) = crate::process::descriptor(dirfd) else {
+        return fail(Errno::BadF);
+    };
+    let path = cstr_string(pathname);
+    match crate::fs::join_beneath(&crate::fs::path(&directory), &path) {
+        Ok(path) => open_path(path, flags),
+        Err(errno) => fail(errno),
+    }
 }
 
 #[repr(C)]
@@ -211,19 +438,24 @@ struct IOVector {
 
 #[unsafe(no_mangle)]
 extern "C" fn readv(fd: i32, bufs: *mut IOVector, bufcnt: i32) -> i64 {
+    let _kernel = KernelSection::enter();
     debug_println!("(readv)");
-    match fd {
-        1 | 2 => 0,
-        0 => 0,
-        handle => {
+    match crate::process::descriptor(fd) {
+        None => fail(Errno::BadF),
+        Some(Descriptor::Console) => unsafe {
+            let iovecs = core::slice::from_raw_parts(bufs, bufcnt as usize);
+            read_console(iovecs) as i64
+        },
+        Some(Descriptor::File(file)) => {
             let mut count = 0;
             unsafe {
                 let iovecs = core::slice::from_raw_parts(bufs, bufcnt as usize);
                 for iovec in iovecs {
                     let slice = core::slice::from_raw_parts_mut(iovec.base, iovec.size);
-                    let wasread = crate::fs::read(handle as isize, slice);
-                    return_if!(wasread < 0, wasread as i64);
-                    count += wasread;
+                    match crate::fs::read(&file, slice) {
+                        Ok(wasread) => count += wasread,
+                        Err(errno) => return fail(errno),
+                    }
                 }
             }
             count as i64
@@ -231,11 +463,41 @@ extern "C" fn readv(fd: i32, bufs: *mut IOVector, bufcnt: i32) -> i64 {
     }
 }
 
+/// Blocks until the serial port has input, letting other threads run meanwhile,
+/// then reads whatever has arrived.
+fn read_console(iovecs: &[IOVector]) -> usize {
+    let mut slots = iovecs
+        .iter()
+        .filter(|iovec| iovec.size > 0)
+        .flat_map(|iovec| unsafe { core::slice::from_raw_parts_mut(iovec.base, iovec.size) });
+    let Some(first) = slots.next() else {
+        return 0;
+    };
+    *first = loop {
+        match crate::serial::serial_recv() {
+            Some(byte) => break byte,
+            None => crate::sched::yield_now(),
+        }
+    };
+    let mut count = 1;
+    // Only take a byte off the port once there's a slot to put it in.
+    for slot in slots {
+        match crate::serial::serial_recv() {
+            Some(byte) => *slot = byte,
+            None => break,
+        }
+        count += 1;
+    }
+    count
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn writev(fd: i32, bufs: *mut IOVector, bufcnt: i32) -> i64 {
+    let _kernel = KernelSection::enter();
     debug_println!("(writev)");
-    match fd {
-        1 | 2 => {
+    match crate::process::descriptor(fd) {
+        None => fail(Errno::BadF),
+        Some(Descriptor::Console) => {
             let mut count = 0;
             unsafe {
                 let iovecs = core::slice::from_raw_parts(bufs, bufcnt as usize);
@@ -247,16 +509,16 @@ extern "C" fn writev(fd: i32, bufs: *mut IOVector, bufcnt: i32) -> i64 {
             }
             count as i64
         }
-        0 => 0,
-        handle => {
+        Some(Descriptor::File(file)) => {
             let mut count = 0;
             unsafe {
                 let iovecs = core::slice::from_raw_parts(bufs, bufcnt as usize);
                 for iovec in iovecs {
                     let slice = core::slice::from_raw_parts(iovec.base, iovec.size);
-                    let written = crate::fs::write(handle as isize, slice);
-                    return_if!(written < 0, written as i64);
-                    count += written;
+                    match crate::fs::write(&file, slice) {
+                        Ok(written) => count += written,
+                        Err(errno) => return fail(errno),
+                    }
                 }
             }
             count as i64
@@ -266,12 +528,14 @@ extern "C" fn writev(fd: i32, bufs: *mut IOVector, bufcnt: i32) -> i64 {
 
 #[unsafe(no_mangle)]
 extern "C" fn fdatasync(_fd: i32) -> i32 {
+    let _kernel = KernelSection::enter();
     debug_println!("(fdatasync)");
     0
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn getrandom() -> u64 {
+    let _kernel = KernelSection::enter();
     debug_println!("(getrandom)");
     let mut random_value: u64 = 0;
     unsafe {
@@ -285,14 +549,44 @@ extern "C" fn getrandom() -> u64 {
     random_value
 }
 
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+const CLOCK_PROCESS_CPUTIME_ID: i32 = 2;
+const CLOCK_THREAD_CPUTIME_ID: i32 = 3;
+
+/// Reads a clock in nanoseconds.
+fn clock(clockid: i32) -> Result<u64> {
+    match clockid {
+        CLOCK_REALTIME => Ok(crate::time::realtime()),
+        CLOCK_MONOTONIC => Ok(crate::time::monotonic()),
+        CLOCK_PROCESS_CPUTIME_ID => Ok(crate::sched::cpu_time(true)),
+        CLOCK_THREAD_CPUTIME_ID => Ok(crate::sched::cpu_time(false)),
+        _ => Err(Errno::Inval),
+    }
+}
+
 #[unsafe(no_mangle)]
-extern "C" fn clock_getres(_clockid: i32, _res: *mut u8) -> i32 {
+extern "C" fn clock_getres(clockid: i32, res: *mut [i64; 2]) -> i32 {
+    let _kernel = KernelSection::enter();
     debug_println!("(clock_getres)");
-    -1
+    if let Err(errno) = clock(clockid) {
+        return fail(errno);
+    }
+    if !res.is_null() {
+        unsafe { res.write_unaligned(timespec(crate::time::resolution())) };
+    }
+    0
 }
 
 #[unsafe(no_mangle)]
-extern "C" fn clock_gettime(_clockid: i32, _tp: *mut u8) -> i32 {
+extern "C" fn clock_gettime(clockid: i32, tp: *mut [i64; 2]) -> i32 {
+    let _kernel = KernelSection::enter();
     debug_println!("(clock_gettime)");
-    -1
+    match clock(clockid) {
+        Ok(nanoseconds) => {
+            unsafe { tp.write_unaligned(timespec(nanoseconds)) };
+            0
+        }
+        Err(errno) => fail(errno),
+    }
 }
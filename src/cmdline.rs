@@ -0,0 +1,174 @@
+use spin::Once;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Quiet,
+    Info,
+    Debug,
+}
+
+pub struct Cmdline {
+    pub init: Option<&'static str>,
+    pub log_level: LogLevel,
+    pub stack_size: u32,
+    pub serial_port: u16,
+    pub heap_size: Option<usize>,
+    raw: &'static str,
+}
+
+static CMDLINE: Once<Cmdline> = Once::new();
+
+/// Returns the parsed kernel command line.
+///
+/// # Safety
+///
+/// Doesn't allocate, so it can be called before `mm_init`.
+pub fn get() -> &'static Cmdline {
+    CMDLINE.call_once(|| parse(crate::boot::cmdline()))
+}
+
+fn parse(raw: &'static str) -> Cmdline {
+    let mut cmdline = Cmdline {
+        init: None,
+        log_level: if cfg!(debug_assertions) {
+            LogLevel::Debug
+        } else {
+            LogLevel::Info
+        },
+        stack_size: 1024 * 64,
+        serial_port: 0x3f8,
+        heap_size: None,
+        raw,
+    };
+    for (key, value) in options(raw) {
+        match (key, value) {
+            ("init", Some(path)) => cmdline.init = Some(path),
+            ("loglevel", Some(level)) => {
+                cmdline.log_level = match level {
+                    "quiet" | "0" => LogLevel::Quiet,
+                    "info" | "1" => LogLevel::Info,
+                    "debug" | "2" => LogLevel::Debug,
+                    _ => cmdline.log_level,
+                }
+            }
+            ("stack", Some(size)) => {
+                if let Some(size) = parse_size(size).and_then(|size| u32::try_from(size).ok()) {
+                    cmdline.stack_size = size;
+                }
+            }
+            ("heap", Some(size)) => cmdline.heap_size = parse_size(size),
+            ("serial", Some(port)) => {
+                cmdline.serial_port = match port {
+                    "com1" => 0x3f8,
+                    "com2" => 0x2f8,
+                    "com3" => 0x3e8,
+                    "com4" => 0x2e8,
+                    _ => parse_number(port)
+                        .and_then(|port| u16::try_from(port).ok())
+                        .unwrap_or(cmdline.serial_port),
+                }
+            }
+            _ => {}
+        }
+    }
+    cmdline
+}
+
+impl Cmdline {
+    /// Extra arguments for the init program, from every `arg=` option.
+    pub fn args(&self) -> impl Iterator<Item = &'static str> {
+        values(self.raw, "arg")
+    }
+
+    /// Environment variables for the init program, from every `env=` option.
+    pub fn vars(&self) -> impl Iterator<Item = &'static str> {
+        values(self.raw, "env")
+    }
+
+    /// Options `parse` ignored because their values don't fit.
+    /// `parse` runs before the serial port is up, so it can't say so itself.
+    pub fn rejected(&self) -> impl Iterator<Item = (&'static str, &'static str)> {
+        options(self.raw).filter_map(|option| match option {
+            ("stack", Some(size))
+                if parse_size(size).is_some_and(|size| u32::try_from(size).is_err()) =>
+            {
+                Some(("stack", size))
+            }
+            ("serial", Some(port))
+                if parse_number(port).is_some_and(|port| u16::try_from(port).is_err()) =>
+            {
+                Some(("serial", port))
+            }
+            _ => None,
+        })
+    }
+}
+
+/// Returns the value of every `key=value` option with the given key.
+pub fn values<'a>(raw: &'a str, key: &'a str) -> impl Iterator<Item = &'a str> {
+    options(raw).filter_map(move |option| match option {
+        (option_key, Some(value)) if option_key == key => Some(value),
+        _ => None,
+    })
+}
+
+/// Splits a command line into `key=value` options.
+/// Values may be wrapped in double quotes to include spaces.
+pub fn options(raw: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    let mut rest = raw;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let key_end = rest.find(['=', ' ']).unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        if !rest[key_end..].starts_with('=') {
+            rest = &rest[key_end..];
+            return Some((key, None));
+        }
+        let value = &rest[key_end + 1..];
+        let (value, remainder) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => {
+                let end = value.find(' ').unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            }
+        };
+        rest = remainder;
+        Some((key, Some(value)))
+    })
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Parses a duration into nanoseconds, with an `ms`, `us` or `s` suffix. Plain numbers are seconds.
+pub fn parse_duration(text: &str) -> Option<u64> {
+    let (number, scale) = if let Some(number) = text.strip_suffix("ms") {
+        (number, 1_000_000)
+    } else if let Some(number) = text.strip_suffix("us") {
+        (number, 1_000)
+    } else {
+        (text.strip_suffix('s').unwrap_or(text), 1_000_000_000)
+    };
+    (parse_number(number)? as u64).checked_mul(scale)
+}
+
+/// Parses a byte count with an optional `K`, `M` or `G` suffix.
+pub fn parse_size(text: &str) -> Option<usize> {
+    let (number, shift) = match text.as_bytes().last()? {
+        b'K' | b'k' => (&text[..text.len() - 1], 10),
+        b'M' | b'm' => (&text[..text.len() - 1], 20),
+        b'G' | b'g' => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    parse_number(number)?.checked_mul(1 << shift)
+}
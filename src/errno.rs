@@ -0,0 +1,93 @@
+/// Why a kernel call failed.
+///
+/// # Safety
+///
+/// `posix` numbers it the way musl does, for `errno`, and `wasi` the way WASI does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Errno {
+    /// Not allowed.
+    Perm,
+    /// Out of memory, or over the process's memory limit.
+    NoMem,
+    /// No such file or directory.
+    NoEnt,
+    /// Not an open file descriptor, or not open for this kind of access.
+    BadF,
+    /// A pointer outside the program's memory.
+    Fault,
+    /// In use, like a directory a file system is mounted on.
+    Busy,
+    /// Already exists.
+    Exist,
+    /// Crosses from one file system into another.
+    XDev,
+    /// Not a directory.
+    NotDir,
+    /// Is a directory.
+    IsDir,
+    /// An invalid argument.
+    Inval,
+    /// Too many open files, past `fs::MAX_FDS`.
+    MFile,
+    /// A file would grow past the largest size the file system holds.
+    FBig,
+    /// No room left on the file system.
+    NoSpc,
+    /// Can't seek on this descriptor.
+    SPipe,
+    /// Directory not empty.
+    NotEmpty,
+    /// A value too large for the type it's returned in.
+    Overflow,
+    /// A path that leaves the directory it's resolved in. Only WASI has a number for this.
+    NotCapable,
+}
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+impl Errno {
+    pub fn posix(self) -> i32 {
+        match self {
+            Errno::Perm | Errno::NotCapable => 1,
+            Errno::NoEnt => 2,
+            Errno::NoMem => 12,
+            Errno::BadF => 9,
+            Errno::Fault => 14,
+            Errno::Busy => 16,
+            Errno::Exist => 17,
+            Errno::XDev => 18,
+            Errno::NotDir => 20,
+            Errno::IsDir => 21,
+            Errno::Inval => 22,
+            Errno::MFile => 24,
+            Errno::FBig => 27,
+            Errno::NoSpc => 28,
+            Errno::SPipe => 29,
+            Errno::NotEmpty => 39,
+            Errno::Overflow => 75,
+        }
+    }
+
+    pub fn wasi(self) -> u32 {
+        match self {
+            Errno::Perm => 63,
+            Errno::NoMem => 48,
+            Errno::NoEnt => 44,
+            Errno::BadF => 8,
+            Errno::Fault => 21,
+            Errno::Busy => 10,
+            Errno::Exist => 20,
+            Errno::XDev => 75,
+            Errno::NotDir => 54,
+            Errno::IsDir => 31,
+            Errno::Inval => 28,
+            Errno::MFile => 41,
+            Errno::FBig => 22,
+            Errno::NoSpc => 51,
+            Errno::SPipe => 70,
+            Errno::NotEmpty => 55,
+            Errno::Overflow => 61,
+            Errno::NotCapable => 76,
+        }
+    }
+}
@@ -1,150 +1,517 @@
+use crate::errno::{Errno, Result};
 use crate::return_if;
 use alloc::collections::BTreeMap;
+use alloc::format;
 use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
-use spin::Mutex;
+use spin::{Mutex, Once};
 
+pub mod initrd;
+pub mod tmpfs;
+
+/// A mountable file system.
+pub trait FileSystem: Send + Sync {
+    fn root(&self) -> Arc<dyn Inode>;
+    /// Moves an entry in one step, replacing whatever is at `to` if it's the same type.
+    /// Both paths are relative to this file system's root.
+    fn rename(&self, from: &[&str], to: &[&str]) -> Result<()>;
+}
+
+/// A file or directory in some file system, shared by everything that has it open.
+///
+/// # Safety
+///
+/// Methods that don't apply to the inode's type fail with `IsDir` or `NotDir`, and `lookup` with `None`.
+pub trait Inode: Send + Sync {
+    fn file_type(&self) -> FileType;
+    fn size(&self) -> usize;
+    fn metadata(&self) -> Metadata;
+    fn read_at(&self, offset: usize, bytes: &mut [u8]) -> Result<usize>;
+    fn write_at(&self, offset: usize, bytes: &[u8]) -> Result<usize>;
+    /// Writes at the end of the file in one step and returns the new size.
+    fn append(&self, bytes: &[u8]) -> Result<usize>;
+    /// Cuts a file down or pads it with zeroes to exactly `size` bytes.
+    fn truncate(&self, size: usize) -> Result<()>;
+    fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>>;
+    /// Creates an empty file or directory. Fails if the name is taken.
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>>;
+    /// Removes an entry. Fails for directories that aren't empty.
+    fn unlink(&self, name: &str) -> Result<()>;
+    fn readdir(&self) -> Result<Vec<DirectoryEntry>>;
+}
+
+/// An open file: the inode it refers to plus a position of its own.
 pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    /// Where the inode was opened from, so paths can be resolved relative to a directory.
     path: String,
     position: usize,
-    data: Vec<u8>,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+/// What `stat` reports about an inode.
+#[derive(Clone, Copy)]
+pub struct Metadata {
+    /// Unique among the inodes of every mounted file system.
+    pub id: u64,
+    pub file_type: FileType,
+    pub size: usize,
+    pub links: u64,
+    pub times: Timestamps,
+}
+
+/// When an inode was last read, last written, and last changed in any way, in kernel clock nanoseconds.
+#[derive(Clone, Copy)]
+pub struct Timestamps {
+    pub accessed: u64,
+    pub modified: u64,
+    pub changed: u64,
+}
+
+impl Timestamps {
+    pub fn now() -> Self {
+        let now = crate::time::now();
+        Self {
+            accessed: now,
+            modified: now,
+            changed: now,
+        }
+    }
+
+    pub fn access(&mut self) {
+        self.accessed = crate::time::now();
+    }
+
+    pub fn modify(&mut self) {
+        self.modified = crate::time::now();
+        self.changed = self.modified;
+    }
+
+    /// Marks a change to the inode itself, like a rename, rather than to its contents.
+    pub fn change(&mut self) {
+        self.changed = crate::time::now();
+    }
+}
+
+pub struct DirectoryEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// What a file descriptor refers to. Clones share the same open file, and so the same position.
+#[derive(Clone)]
+pub enum Descriptor {
+    /// The serial console, for stdin, stdout and stderr.
+    Console,
+    File(Arc<Mutex<OpenFile>>),
+}
+
+/// The largest a file can grow, and the furthest a file position can be set.
+pub const MAX_FILE_SIZE: usize = 1 << 30;
+
+/// How many file descriptors a process can have open, like `RLIMIT_NOFILE`.
+pub const MAX_FDS: usize = 1024;
+
+/// Maps a process's file descriptors to what they refer to.
+#[derive(Default)]
+pub struct FdTable {
+    descriptors: Vec<Option<Descriptor>>,
+}
+
+/// Mounted file systems, keyed by the absolute path they're mounted at.
+static MOUNTS: Mutex<BTreeMap<String, Arc<dyn FileSystem>>> = Mutex::new(BTreeMap::new());
+
+/// The tmpfs mounted at the root, kept so boot modules can be added to it without copying.
+static ROOT: Once<Arc<tmpfs::TmpFs>> = Once::new();
+
+/// Mounts a tmpfs at the root, and another one at `/tmp`.
+/// The root is filled from the initrd if there is one; its files are
+/// copied only when they are first written to.
+pub fn fs_init(initrd: Option<&'static [u8]>) {
+    let root = tmpfs::TmpFs::new();
+    if let Some(archive) = initrd
+        && !initrd::unpack(archive, &root)
+    {
+        crate::info_println!("initrd: not a tar or newc cpio archive");
+    }
+    root.insert_directory("/tmp");
+    let root = ROOT.call_once(|| Arc::new(root));
+    mount("/", root.clone()).unwrap();
+    mount("/tmp", Arc::new(tmpfs::TmpFs::new())).unwrap();
+}
+
+/// Mounts a file system over an existing directory, or at the root.
+pub fn mount(path: &str, file_system: Arc<dyn FileSystem>) -> Result<()> {
+    let path = normalize(path);
+    if path != "/" {
+        match lookup(&path).map(|inode| inode.file_type()) {
+            None => return Err(Errno::NoEnt),
+            Some(FileType::File) => return Err(Errno::NotDir),
+            Some(FileType::Directory) => {}
+        }
+    }
+    MOUNTS.lock().insert(path, file_system);
+    Ok(())
+}
+
+fn mounted_root(path: &str) -> Option<Arc<dyn Inode>> {
+    Some(MOUNTS.lock().get(path)?.root())
+}
+
+/// Finds the file system a path is in, and how many of its components lead to the mount point.
+fn mount_of(components: &[&str]) -> Option<(Arc<dyn FileSystem>, usize)> {
+    let mounts = MOUNTS.lock();
+    (0..=components.len()).rev().find_map(|depth| {
+        let mount = format!("/{}", components[..depth].join("/"));
+        Some((mounts.get(&mount)?.clone(), depth))
+    })
+}
+
+/// Splits a path into its components, dropping empty ones and `.` and resolving `..`.
+/// Every path is treated as relative to the root, and `..` at the root stays there.
+pub fn components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components
+}
+
+/// Joins a path onto a directory's path. Absolute paths replace the directory.
+pub fn join(directory: &str, path: &str) -> String {
+    if path.starts_with('/') {
+        String::from(path)
+    } else {
+        format!("{directory}/{path}")
+    }
+}
+
+/// Resolves a path inside a directory the way `openat` does for a capability.
+/// Absolute paths, and `..` that would climb out of the directory, are refused.
+pub fn join_beneath(directory: &str, path: &str) -> Result<String> {
+    return_if!(path.starts_with('/'), Err(Errno::NotCapable));
+    let mut depth = 0usize;
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => depth = depth.checked_sub(1).ok_or(Errno::NotCapable)?,
+            _ => depth += 1,
+        }
+    }
+    Ok(normalize(&join(directory, path)))
+}
+
+/// Turns a path into an absolute one with no `.`, `..` or repeated slashes.
+pub fn normalize(path: &str) -> String {
+    format!("/{}", components(path).join("/"))
 }
 
-static FILE_SYSTEM: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
-static HANDLES: Mutex<BTreeMap<isize, OpenFile>> = Mutex::new(BTreeMap::new());
-static NEXT_HANDLE: Mutex<isize> = Mutex::new(3);
+/// Follows a path from the root, crossing into mounted file systems along the way.
+fn walk(components: &[&str]) -> Option<Arc<dyn Inode>> {
+    let mut inode = mounted_root("/")?;
+    let mut path = String::new();
+    for component in components {
+        path.push('/');
+        path.push_str(component);
+        inode = match mounted_root(&path) {
+            Some(root) => root,
+            None => inode.lookup(component)?,
+        };
+    }
+    Some(inode)
+}
+
+pub fn lookup(path: &str) -> Option<Arc<dyn Inode>> {
+    walk(&components(path))
+}
 
-//pub fn fs_init() {}
+/// Finds the directory a path's last component would go in, and that component.
+/// The root itself has no parent.
+fn lookup_parent(path: &str) -> Option<(Arc<dyn Inode>, String)> {
+    let components = components(path);
+    let (name, parents) = components.split_last()?;
+    Some((walk(parents)?, String::from(*name)))
+}
+
+/// Adds a file to the root file system, replacing any existing one.
+/// Its contents are borrowed rather than copied, like the initrd's.
+/// Missing parent directories are created along the way.
+pub fn create(name: &str, data: &'static [u8]) {
+    if let Some(root) = ROOT.get() {
+        root.insert_file(name, data);
+    }
+}
 
+/// Creates an empty directory.
+pub fn mkdir(path: &str) -> Result<()> {
+    return_if!(components(path).is_empty(), Err(Errno::Exist));
+    let (parent, name) = lookup_parent(path).ok_or(Errno::NoEnt)?;
+    parent.create(&name, FileType::Directory)?;
+    Ok(())
+}
+
+/// Removes an empty directory. Mount points can't be removed.
+pub fn rmdir(path: &str) -> Result<()> {
+    return_if!(components(path).is_empty(), Err(Errno::Perm));
+    return_if!(mounted_root(&normalize(path)).is_some(), Err(Errno::Busy));
+    let (parent, name) = lookup_parent(path).ok_or(Errno::NoEnt)?;
+    match parent.lookup(&name).map(|inode| inode.file_type()) {
+        None => Err(Errno::NoEnt),
+        Some(FileType::File) => Err(Errno::NotDir),
+        Some(FileType::Directory) => parent.unlink(&name),
+    }
+}
+
+/// Removes a file. Directories need `rmdir`.
+pub fn unlink(path: &str) -> Result<()> {
+    return_if!(components(path).is_empty(), Err(Errno::IsDir));
+    return_if!(mounted_root(&normalize(path)).is_some(), Err(Errno::Busy));
+    let (parent, name) = lookup_parent(path).ok_or(Errno::NoEnt)?;
+    match parent.lookup(&name).map(|inode| inode.file_type()) {
+        None => Err(Errno::NoEnt),
+        Some(FileType::Directory) => Err(Errno::IsDir),
+        Some(FileType::File) => parent.unlink(&name),
+    }
+}
+
+/// Moves a file or directory. Both paths must be in the same file system,
+/// and neither can be a mount point.
+pub fn rename(from: &str, to: &str) -> Result<()> {
+    return_if!(
+        mounted_root(&normalize(from)).is_some() || mounted_root(&normalize(to)).is_some(),
+        Err(Errno::Busy)
+    );
+    let from = components(from);
+    let to = components(to);
+    let (file_system, from_depth) = mount_of(&from).ok_or(Errno::NoEnt)?;
+    let (to_file_system, to_depth) = mount_of(&to).ok_or(Errno::NoEnt)?;
+    return_if!(
+        !Arc::ptr_eq(&file_system, &to_file_system),
+        Err(Errno::XDev)
+    );
+    return_if!(from_depth != to_depth, Err(Errno::XDev));
+    file_system.rename(&from[from_depth..], &to[to_depth..])
+}
+
+/// Lists an open directory, starting with `.` and `..`.
+pub fn readdir(file: &Mutex<OpenFile>) -> Result<Vec<DirectoryEntry>> {
+    let entries = file.lock().inode.readdir()?;
+    let mut listing = Vec::from([
+        DirectoryEntry {
+            name: String::from("."),
+            file_type: FileType::Directory,
+        },
+        DirectoryEntry {
+            name: String::from(".."),
+            file_type: FileType::Directory,
+        },
+    ]);
+    listing.extend(entries);
+    Ok(listing)
+}
+
+/// Returns the metadata of an open file or directory.
+pub fn metadata(file: &Mutex<OpenFile>) -> Metadata {
+    file.lock().inode.metadata()
+}
+
+/// Returns the metadata of whatever is at `path`.
+pub fn stat(path: &str) -> Result<Metadata> {
+    Ok(lookup(path).ok_or(Errno::NoEnt)?.metadata())
+}
+
+/// Returns the path an open file or directory was opened from.
+pub fn path(file: &Mutex<OpenFile>) -> String {
+    file.lock().path.clone()
+}
+
+impl FdTable {
+    /// Creates a table with stdin, stdout and stderr at 0, 1 and 2.
+    pub fn new() -> Self {
+        Self {
+            descriptors: vec![Some(Descriptor::Console); 3],
+        }
+    }
+
+    pub fn get(&self, fd: i32) -> Option<Descriptor> {
+        self.descriptors.get(usize::try_from(fd).ok()?)?.clone()
+    }
+
+    /// Puts a descriptor at the lowest free fd that is at least `minimum`.
+    /// Fails with `MFile` if every fd below `MAX_FDS` from there on is taken.
+    fn insert_from(&mut self, descriptor: Descriptor, minimum: usize) -> Result<i32> {
+        let fd = (minimum..self.descriptors.len())
+            .find(|fd| self.descriptors[*fd].is_none())
+            .unwrap_or(self.descriptors.len().max(minimum));
+        return_if!(fd >= MAX_FDS, Err(Errno::MFile));
+        if fd >= self.descriptors.len() {
+            self.descriptors.resize(fd + 1, None);
+        }
+        self.descriptors[fd] = Some(descriptor);
+        Ok(fd as i32)
+    }
+
+    /// Puts a descriptor at the lowest free fd.
+    pub fn insert(&mut self, descriptor: Descriptor) -> Result<i32> {
+        self.insert_from(descriptor, 0)
+    }
+
+    pub fn remove(&mut self, fd: i32) -> Option<Descriptor> {
+        self.descriptors.get_mut(usize::try_from(fd).ok()?)?.take()
+    }
+
+    /// Copies `fd` to the lowest free fd that is at least `minimum`, like `F_DUPFD`.
+    /// Fails with `BadF` if `fd` isn't open, `Inval` if `minimum` is negative or past `MAX_FDS`,
+    /// and `MFile` if there's no free fd left from `minimum` on.
+    pub fn dup(&mut self, fd: i32, minimum: i32) -> Result<i32> {
+        let descriptor = self.get(fd).ok_or(Errno::BadF)?;
+        let minimum = usize::try_from(minimum)
+            .ok()
+            .filter(|minimum| *minimum < MAX_FDS)
+            .ok_or(Errno::Inval)?;
+        self.insert_from(descriptor, minimum)
+    }
+
+    /// Puts a descriptor at `fd`, closing whatever was there.
+    pub fn insert_at(&mut self, fd: i32, descriptor: Descriptor) -> Option<i32> {
+        let index = usize::try_from(fd).ok().filter(|index| *index < MAX_FDS)?;
+        if index >= self.descriptors.len() {
+            self.descriptors.resize(index + 1, None);
+        }
+        self.descriptors[index] = Some(descriptor);
+        Some(fd)
+    }
+
+    /// Copies `fd` to `new_fd`, closing whatever was there, like `dup2`.
+    pub fn dup2(&mut self, fd: i32, new_fd: i32) -> Option<i32> {
+        let descriptor = self.get(fd)?;
+        self.insert_at(new_fd, descriptor)
+    }
+
+    /// Moves `fd` to `new_fd`, like WASI's `fd_renumber`, and returns whatever was at `new_fd`
+    /// for the caller to close.
+    pub fn renumber(&mut self, fd: i32, new_fd: i32) -> Option<Option<Descriptor>> {
+        return_if!(fd == new_fd, self.get(fd).map(|_| None));
+        self.get(fd)?;
+        let replaced = self.remove(new_fd);
+        self.dup2(fd, new_fd)?;
+        self.remove(fd);
+        Some(replaced)
+    }
+}
+
+#[derive(Default)]
 pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
     pub append: bool,
+    pub create: bool,
     pub exclude: bool,
     pub truncate: bool,
+    /// Fail unless the path is a directory.
+    pub directory: bool,
 }
 
-pub fn open(name: String, open_flags: OpenFlags) -> isize {
-    let mut next_handle = NEXT_HANDLE.lock();
-    let mut file_system = FILE_SYSTEM.lock();
-    let mut handles = HANDLES.lock();
-    return_if!(open_flags.exclude && file_system.get(&name).is_some(), -1);
-    let handle = *next_handle;
-    *next_handle += 1;
-    handles.insert(
-        handle,
-        OpenFile {
-            position: if open_flags.append && !open_flags.truncate {
-                match file_system.get(&name) {
-                    Some(data) => data.len(),
-                    None => 0,
-                }
-            } else {
-                0
-            },
-            data: match file_system.get_mut(&name) {
-                Some(data) => data.clone(),
-                None => {
-                    file_system.insert(name.clone(), Vec::new());
-                    Vec::new()
-                }
-            },
-            path: name,
-        },
-    );
-    handle
-}
-
-pub fn close(handle: isize) {
-    let mut file_system = FILE_SYSTEM.lock();
-    let mut handles = HANDLES.lock();
-    let open_file = handles.get(&handle).unwrap();
-    file_system.insert(open_file.path.clone(), open_file.data.clone());
-    handles.remove(&handle);
-}
-
-pub fn seek(handle: isize, offset: isize, whence: i32) -> isize {
-    let mut handles = HANDLES.lock();
-    match handles.get_mut(&handle) {
-        Some(open_file) => {
-            match whence {
-                0 => open_file.position = offset.max(0) as usize,
-                1 => open_file.position = open_file.position.saturating_add_signed(offset),
-                2 => open_file.position = open_file.data.len().saturating_add_signed(offset),
-                _ => return -1,
-            };
-            open_file.position as isize
-        }
-        None => -1,
-    }
-}
-
-pub fn write(handle: isize, bytes: &[u8]) -> isize {
-    let mut handles = HANDLES.lock();
-    match handles.get_mut(&handle) {
-        Some(open_file) => {
-            if open_file.position + bytes.len() <= open_file.data.len() {
-                let last_position = open_file.position + bytes.len();
-                open_file
-                    .data
-                    .get_mut(open_file.position..last_position)
-                    .unwrap()
-                    .clone_from_slice(bytes);
-            } else if open_file.position > open_file.data.len() {
-                open_file
-                    .data
-                    .append(&mut [0].repeat(open_file.position - open_file.data.len()));
-                open_file.data.append(&mut bytes.to_vec().clone());
-            } else {
-                let last_position = open_file.position + bytes.len();
-                let middle_position = open_file.data.len() - open_file.position;
-                open_file
-                    .data
-                    .get_mut(open_file.position..last_position)
-                    .unwrap()
-                    .clone_from_slice(bytes.get(0..middle_position).unwrap());
-                open_file.data.append(
-                    &mut bytes
-                        .get(middle_position..bytes.len())
-                        .unwrap()
-                        .to_vec()
-                        .clone(),
-                );
-            }
-            open_file.position += bytes.len();
-            bytes.len() as isize
+/// Opens a file or directory, creating an empty file if asked to.
+pub fn open(name: String, open_flags: OpenFlags) -> Result<Descriptor> {
+    let path = normalize(&name);
+    // Found through `lookup`, so a mount point opens the file system mounted there.
+    let inode = match lookup(&path) {
+        Some(_) if open_flags.create && open_flags.exclude => return Err(Errno::Exist),
+        Some(inode) => inode,
+        None if open_flags.create && !open_flags.directory => {
+            let (parent, name) = lookup_parent(&path).ok_or(Errno::NoEnt)?;
+            parent.create(&name, FileType::File)?
         }
-        None => -1,
-    }
-}
-
-pub fn read(handle: isize, bytes: &mut [u8]) -> isize {
-    let mut handles = HANDLES.lock();
-    match handles.get_mut(&handle) {
-        Some(open_file) => {
-            if open_file.position + bytes.len() > open_file.data.len() {
-                let data_len = open_file.data.len();
-                let length = data_len - bytes.len();
-                bytes.get_mut(0..length).unwrap().clone_from_slice(
-                    open_file
-                        .data
-                        .get_mut(open_file.position..data_len)
-                        .unwrap(),
-                );
-                open_file.position = data_len;
-                length as isize
-            } else if open_file.position >= open_file.data.len() {
-                0
-            } else {
-                let last_position = open_file.position + bytes.len();
-                bytes.clone_from_slice(
-                    open_file
-                        .data
-                        .get_mut(open_file.position..last_position)
-                        .unwrap(),
-                );
-                open_file.position += bytes.len();
-                bytes.len() as isize
-            }
-        }
-        None => -1,
+        None => return Err(Errno::NoEnt),
+    };
+    match inode.file_type() {
+        FileType::File if open_flags.directory => return Err(Errno::NotDir),
+        FileType::Directory if open_flags.write => return Err(Errno::IsDir),
+        _ => {}
     }
+    if open_flags.truncate && open_flags.write && inode.file_type() == FileType::File {
+        inode.truncate(0)?;
+    }
+    let open_file = OpenFile {
+        position: if open_flags.append { inode.size() } else { 0 },
+        inode,
+        path,
+        readable: open_flags.read,
+        writable: open_flags.write,
+        append: open_flags.append,
+    };
+    Ok(Descriptor::File(Arc::new(Mutex::new(open_file))))
+}
+
+/// Opens an existing directory.
+pub fn open_directory(path: &str) -> Result<Descriptor> {
+    open(
+        String::from(path),
+        OpenFlags {
+            read: true,
+            directory: true,
+            ..OpenFlags::default()
+        },
+    )
+}
+
+/// Sets an open file's size, like `ftruncate`. Its position stays where it was.
+pub fn truncate(file: &Mutex<OpenFile>, size: usize) -> Result<()> {
+    let open_file = file.lock();
+    return_if!(!open_file.writable, Err(Errno::BadF));
+    open_file.inode.truncate(size)
+}
+
+/// Moves the file's position. Positions before the start or past `MAX_FILE_SIZE` are `Inval`.
+pub fn seek(file: &Mutex<OpenFile>, offset: isize, whence: i32) -> Result<usize> {
+    let mut open_file = file.lock();
+    let base = match whence {
+        0 => 0,
+        1 => open_file.position,
+        2 => open_file.inode.size(),
+        _ => return Err(Errno::Inval),
+    };
+    open_file.position = base
+        .checked_add_signed(offset)
+        .filter(|position| *position <= MAX_FILE_SIZE)
+        .ok_or(Errno::Inval)?;
+    Ok(open_file.position)
+}
+
+/// Writes at the file's position, or at the end if it was opened for appending.
+pub fn write(file: &Mutex<OpenFile>, bytes: &[u8]) -> Result<usize> {
+    let mut open_file = file.lock();
+    return_if!(!open_file.writable, Err(Errno::BadF));
+    if open_file.append {
+        open_file.position = open_file.inode.append(bytes)?;
+        return Ok(bytes.len());
+    }
+    let written = open_file.inode.write_at(open_file.position, bytes)?;
+    open_file.position += written;
+    Ok(written)
+}
+
+pub fn read(file: &Mutex<OpenFile>, bytes: &mut [u8]) -> Result<usize> {
+    let mut open_file = file.lock();
+    return_if!(!open_file.readable, Err(Errno::BadF));
+    let length = open_file.inode.read_at(open_file.position, bytes)?;
+    open_file.position += length;
+    Ok(length)
 }
@@ -0,0 +1,110 @@
+use super::tmpfs::TmpFs;
+use crate::info_println;
+
+/// Unpacks a USTAR or newc cpio archive into a tmpfs.
+/// File contents aren't copied until something writes to them.
+///
+/// # Safety
+///
+/// Returns false if the archive is in neither format.
+pub fn unpack(archive: &'static [u8], tmpfs: &TmpFs) -> bool {
+    if archive.starts_with(b"070701") || archive.starts_with(b"070702") {
+        unpack_cpio(archive, tmpfs);
+    } else if archive.get(257..262) == Some(b"ustar") {
+        unpack_tar(archive, tmpfs);
+    } else {
+        return false;
+    }
+    true
+}
+
+fn parse_hex(field: &[u8]) -> Option<usize> {
+    usize::from_str_radix(core::str::from_utf8(field).ok()?, 16).ok()
+}
+
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let text = core::str::from_utf8(field).ok()?;
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    match text {
+        "" => Some(0),
+        text => usize::from_str_radix(text, 8).ok(),
+    }
+}
+
+/// Returns a NUL-terminated field as a string.
+fn parse_string(field: &[u8]) -> Option<&str> {
+    let length = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    core::str::from_utf8(&field[..length]).ok()
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    value.div_ceil(alignment) * alignment
+}
+
+/// Adds the entries of a "newc" cpio archive, as made by `cpio -H newc`.
+fn unpack_cpio(archive: &'static [u8], tmpfs: &TmpFs) {
+    const HEADER_SIZE: usize = 110;
+    let mut offset = 0;
+    while let Some(header) = archive.get(offset..offset + HEADER_SIZE) {
+        let (Some(mode), Some(size), Some(name_size)) = (
+            parse_hex(&header[14..22]),
+            parse_hex(&header[54..62]),
+            parse_hex(&header[94..102]),
+        ) else {
+            break;
+        };
+        let name_start = offset + HEADER_SIZE;
+        let Some(name) = archive
+            .get(name_start..name_start + name_size)
+            .and_then(parse_string)
+        else {
+            break;
+        };
+        if name == "TRAILER!!!" {
+            break;
+        }
+        let data_start = align_up(name_start + name_size, 4);
+        let Some(data) = archive.get(data_start..data_start + size) else {
+            break;
+        };
+        match mode & 0o170000 {
+            0o040000 => tmpfs.insert_directory(name),
+            0o100000 => tmpfs.insert_file(name, data),
+            _ => info_println!("initrd: skipping {name}"),
+        }
+        offset = align_up(data_start + size, 4);
+    }
+}
+
+/// Adds the entries of a USTAR archive.
+fn unpack_tar(archive: &'static [u8], tmpfs: &TmpFs) {
+    const BLOCK_SIZE: usize = 512;
+    let mut offset = 0;
+    while let Some(header) = archive.get(offset..offset + BLOCK_SIZE) {
+        // The archive ends with blocks of zeroes.
+        if header[0] == 0 {
+            break;
+        }
+        let (Some(name), Some(prefix), Some(size)) = (
+            parse_string(&header[0..100]),
+            parse_string(&header[345..500]),
+            parse_octal(&header[124..136]),
+        ) else {
+            break;
+        };
+        let data_start = offset + BLOCK_SIZE;
+        let Some(data) = archive.get(data_start..data_start + size) else {
+            break;
+        };
+        let path = alloc::format!("{prefix}/{name}");
+        match header[156] {
+            b'0' | 0 => tmpfs.insert_file(&path, data),
+            b'5' => tmpfs.insert_directory(&path),
+            _ => info_println!("initrd: skipping {path}"),
+        }
+        offset = data_start + align_up(size, BLOCK_SIZE);
+    }
+}
@@ -0,0 +1,430 @@
+use super::{DirectoryEntry, FileSystem, FileType, Inode, Metadata, Timestamps};
+use crate::errno::{Errno, Result};
+use crate::process::Pid;
+use crate::return_if;
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// A file system that lives entirely in the kernel heap.
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+    /// Renames touch two directories, so they take turns.
+    renaming: Mutex<()>,
+}
+
+struct TmpInode {
+    id: u64,
+    times: Mutex<Timestamps>,
+    contents: Contents,
+}
+
+enum Contents {
+    File(Mutex<FileData>),
+    Directory(Mutex<BTreeMap<String, Arc<TmpInode>>>),
+}
+
+/// A file's contents, and who pays for them.
+struct FileData {
+    /// Contents borrowed from boot memory stay borrowed until the first write.
+    bytes: Cow<'static, [u8]>,
+    /// The processes the owned bytes are charged to and how many each, latest last.
+    /// Borrowed contents cost the heap nothing, so they aren't charged.
+    charges: Vec<(Option<Pid>, usize)>,
+}
+
+/// Inode numbers are unique across every tmpfs, so mounts don't need device numbers.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+impl TmpFs {
+    pub fn new() -> Self {
+        Self {
+            root: Arc::new(TmpInode::directory()),
+            renaming: Mutex::new(()),
+        }
+    }
+
+    /// Adds a file whose contents are borrowed rather than copied,
+    /// creating missing parent directories and replacing any existing file.
+    pub fn insert_file(&self, path: &str, data: &'static [u8]) {
+        let components = super::components(path);
+        let Some((name, parents)) = components.split_last() else {
+            return;
+        };
+        if let Some(directory) = self.directory(parents)
+            && let Contents::Directory(entries) = &directory.contents
+        {
+            entries.lock().insert(
+                String::from(*name),
+                Arc::new(TmpInode::new(Contents::File(Mutex::new(FileData::new(
+                    Cow::Borrowed(data),
+                ))))),
+            );
+        }
+    }
+
+    /// Creates a directory and any missing parents.
+    pub fn insert_directory(&self, path: &str) {
+        self.directory(&super::components(path));
+    }
+
+    fn directory(&self, components: &[&str]) -> Option<Arc<TmpInode>> {
+        let mut directory = self.root.clone();
+        for component in components {
+            let Contents::Directory(entries) = &directory.contents else {
+                return None;
+            };
+            let next = entries
+                .lock()
+                .entry(String::from(*component))
+                .or_insert_with(|| Arc::new(TmpInode::directory()))
+                .clone();
+            directory = next;
+        }
+        Some(directory)
+    }
+
+    /// Follows a path from the root without creating anything.
+    fn find(&self, components: &[&str]) -> Option<Arc<TmpInode>> {
+        let mut inode = self.root.clone();
+        for component in components {
+            let Contents::Directory(entries) = &inode.contents else {
+                return None;
+            };
+            let next = entries.lock().get(*component)?.clone();
+            inode = next;
+        }
+        Some(inode)
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn rename(&self, from: &[&str], to: &[&str]) -> Result<()> {
+        return_if!(from == to, Ok(()));
+        // A directory can't be moved into itself.
+        return_if!(to.starts_with(from), Err(Errno::Inval));
+        let (Some((name, from_parents)), Some((new_name, to_parents))) =
+            (from.split_last(), to.split_last())
+        else {
+            return Err(Errno::Perm);
+        };
+        let _renaming = self.renaming.lock();
+        let source = self.find(from_parents).ok_or(Errno::NoEnt)?;
+        let target = self.find(to_parents).ok_or(Errno::NoEnt)?;
+        let (Contents::Directory(source_entries), Contents::Directory(target_entries)) =
+            (&source.contents, &target.contents)
+        else {
+            return Err(Errno::NotDir);
+        };
+        let inode = source_entries
+            .lock()
+            .get(*name)
+            .cloned()
+            .ok_or(Errno::NoEnt)?;
+        // The new name goes in before the old one comes out, so `to` never stops existing.
+        // Only one directory is locked at a time, which keeps the lock order parent to child.
+        {
+            let mut target_entries = target_entries.lock();
+            match (
+                inode.file_type(),
+                target_entries.get(*new_name).map(|old| &old.contents),
+            ) {
+                (_, None) | (FileType::File, Some(Contents::File(_))) => {}
+                (FileType::File, Some(Contents::Directory(_))) => return Err(Errno::IsDir),
+                (FileType::Directory, Some(Contents::File(_))) => return Err(Errno::NotDir),
+                (FileType::Directory, Some(Contents::Directory(children))) => {
+                    return_if!(!children.lock().is_empty(), Err(Errno::NotEmpty));
+                }
+            }
+            target_entries.insert(String::from(*new_name), inode.clone());
+        }
+        {
+            let mut source_entries = source_entries.lock();
+            if source_entries
+                .get(*name)
+                .is_some_and(|entry| Arc::ptr_eq(entry, &inode))
+            {
+                source_entries.remove(*name);
+            }
+        }
+        source.times.lock().modify();
+        target.times.lock().modify();
+        inode.times.lock().change();
+        Ok(())
+    }
+}
+
+impl FileData {
+    fn new(bytes: Cow<'static, [u8]>) -> Self {
+        Self {
+            bytes,
+            charges: Vec::new(),
+        }
+    }
+
+    /// How many heap bytes the contents take: none while they're borrowed.
+    fn owned(&self) -> usize {
+        match &self.bytes {
+            Cow::Borrowed(_) => 0,
+            Cow::Owned(owned) => owned.len(),
+        }
+    }
+
+    /// Charges the current process for owning `size` bytes, past what's charged already.
+    fn charge(&mut self, size: usize) -> Result<()> {
+        let charged: usize = self.charges.iter().map(|(_, charge)| charge).sum();
+        return_if!(size <= charged, Ok(()));
+        let pid = crate::sched::current_process();
+        let growth = size - charged;
+        self.charges.try_reserve(1).map_err(|_| Errno::NoSpc)?;
+        return_if!(
+            !crate::process::reserve_memory(pid, growth),
+            Err(Errno::NoMem)
+        );
+        match self.charges.last_mut() {
+            Some((last, charge)) if *last == pid => *charge += growth,
+            _ => self.charges.push((pid, growth)),
+        }
+        Ok(())
+    }
+
+    /// Gives back what's charged past `size` bytes, latest charges first.
+    fn uncharge(&mut self, size: usize) {
+        let charged: usize = self.charges.iter().map(|(_, charge)| charge).sum();
+        let mut excess = charged.saturating_sub(size);
+        while excess > 0
+            && let Some((pid, charge)) = self.charges.last_mut()
+        {
+            let released = excess.min(*charge);
+            crate::process::release_memory(*pid, released);
+            *charge -= released;
+            excess -= released;
+            if *charge == 0 {
+                self.charges.pop();
+            }
+        }
+    }
+
+    /// Makes the contents owned and at least `size` bytes long, charging the current process.
+    /// Fails rather than aborting when the process or the heap can't hold them.
+    fn grow(&mut self, size: usize) -> Result<&mut Vec<u8>> {
+        return_if!(size > super::MAX_FILE_SIZE, Err(Errno::FBig));
+        let owned = self.owned();
+        self.charge(size.max(self.bytes.len()))?;
+        if let Err(errno) = self.reserve(size) {
+            self.uncharge(owned);
+            return Err(errno);
+        }
+        let bytes = self.bytes.to_mut();
+        if size > bytes.len() {
+            bytes.resize(size, 0);
+        }
+        Ok(bytes)
+    }
+
+    fn reserve(&mut self, size: usize) -> Result<()> {
+        match &mut self.bytes {
+            Cow::Borrowed(borrowed) => {
+                let mut owned = Vec::new();
+                owned
+                    .try_reserve_exact(size.max(borrowed.len()))
+                    .map_err(|_| Errno::NoSpc)?;
+                owned.extend_from_slice(borrowed);
+                self.bytes = Cow::Owned(owned);
+            }
+            Cow::Owned(owned) => {
+                owned
+                    .try_reserve(size.saturating_sub(owned.len()))
+                    .map_err(|_| Errno::NoSpc)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Cuts the contents down to `size` bytes. Borrowed contents shrink without being copied.
+    fn shrink(&mut self, size: usize) {
+        match &mut self.bytes {
+            Cow::Borrowed(borrowed) => *borrowed = &borrowed[..size],
+            Cow::Owned(owned) => owned.truncate(size),
+        }
+        self.uncharge(self.owned());
+    }
+}
+
+impl Drop for FileData {
+    fn drop(&mut self) {
+        self.uncharge(0);
+    }
+}
+
+impl TmpInode {
+    fn new(contents: Contents) -> Self {
+        TmpInode {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            times: Mutex::new(Timestamps::now()),
+            contents,
+        }
+    }
+
+    fn directory() -> Self {
+        TmpInode::new(Contents::Directory(Mutex::new(BTreeMap::new())))
+    }
+}
+
+impl Inode for TmpInode {
+    fn file_type(&self) -> FileType {
+        match self.contents {
+            Contents::File(_) => FileType::File,
+            Contents::Directory(_) => FileType::Directory,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match &self.contents {
+            Contents::File(data) => data.lock().bytes.len(),
+            Contents::Directory(entries) => entries.lock().len(),
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        // A directory is linked from its parent, from its own `.`, and from each subdirectory's `..`.
+        let links = match &self.contents {
+            Contents::File(_) => 1,
+            Contents::Directory(entries) => {
+                2 + entries
+                    .lock()
+                    .values()
+                    .filter(|inode| inode.file_type() == FileType::Directory)
+                    .count() as u64
+            }
+        };
+        Metadata {
+            id: self.id,
+            file_type: self.file_type(),
+            size: self.size(),
+            links,
+            times: *self.times.lock(),
+        }
+    }
+
+    fn read_at(&self, offset: usize, bytes: &mut [u8]) -> Result<usize> {
+        let Contents::File(data) = &self.contents else {
+            return Err(Errno::IsDir);
+        };
+        let data = data.lock();
+        self.times.lock().access();
+        return_if!(offset >= data.bytes.len(), Ok(0));
+        let length = bytes.len().min(data.bytes.len() - offset);
+        bytes[..length].copy_from_slice(&data.bytes[offset..offset + length]);
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: usize, bytes: &[u8]) -> Result<usize> {
+        let Contents::File(data) = &self.contents else {
+            return Err(Errno::IsDir);
+        };
+        // Writing nothing doesn't extend the file, even past its end.
+        return_if!(bytes.is_empty(), Ok(0));
+        let mut data = data.lock();
+        let last_position = offset.checked_add(bytes.len()).ok_or(Errno::FBig)?;
+        let data = data.grow(last_position)?;
+        data[offset..last_position].copy_from_slice(bytes);
+        self.times.lock().modify();
+        Ok(bytes.len())
+    }
+
+    fn append(&self, bytes: &[u8]) -> Result<usize> {
+        let Contents::File(data) = &self.contents else {
+            return Err(Errno::IsDir);
+        };
+        let mut data = data.lock();
+        let offset = data.bytes.len();
+        return_if!(bytes.is_empty(), Ok(offset));
+        let last_position = offset.checked_add(bytes.len()).ok_or(Errno::FBig)?;
+        let data = data.grow(last_position)?;
+        data[offset..].copy_from_slice(bytes);
+        self.times.lock().modify();
+        Ok(data.len())
+    }
+
+    fn truncate(&self, size: usize) -> Result<()> {
+        let Contents::File(data) = &self.contents else {
+            return Err(Errno::IsDir);
+        };
+        let mut data = data.lock();
+        if size <= data.bytes.len() {
+            data.shrink(size);
+        } else {
+            data.grow(size)?;
+        }
+        self.times.lock().modify();
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Option<Arc<dyn Inode>> {
+        let Contents::Directory(entries) = &self.contents else {
+            return None;
+        };
+        let inode = entries.lock().get(name)?.clone();
+        Some(inode)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>> {
+        let Contents::Directory(entries) = &self.contents else {
+            return Err(Errno::NotDir);
+        };
+        let mut entries = entries.lock();
+        return_if!(entries.contains_key(name), Err(Errno::Exist));
+        let inode = Arc::new(match file_type {
+            FileType::File => TmpInode::new(Contents::File(Mutex::new(FileData::new(Cow::Owned(
+                Vec::new(),
+            ))))),
+            FileType::Directory => TmpInode::directory(),
+        });
+        entries.insert(String::from(name), inode.clone());
+        self.times.lock().modify();
+        Ok(inode)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        let Contents::Directory(entries) = &self.contents else {
+            return Err(Errno::NotDir);
+        };
+        let mut entries = entries.lock();
+        match entries.get(name).map(|inode| &inode.contents) {
+            Some(Contents::Directory(children)) if !children.lock().is_empty() => {
+                Err(Errno::NotEmpty)
+            }
+            Some(_) => {
+                entries.remove(name);
+                self.times.lock().modify();
+                Ok(())
+            }
+            None => Err(Errno::NoEnt),
+        }
+    }
+
+    fn readdir(&self) -> Result<Vec<DirectoryEntry>> {
+        let Contents::Directory(entries) = &self.contents else {
+            return Err(Errno::NotDir);
+        };
+        let listing = entries
+            .lock()
+            .iter()
+            .map(|(name, inode)| DirectoryEntry {
+                name: name.clone(),
+                file_type: inode.file_type(),
+            })
+            .collect();
+        self.times.lock().access();
+        Ok(listing)
+    }
+}
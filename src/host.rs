@@ -0,0 +1,67 @@
+use crate::wasi::ignore_missing;
+use alloc::vec::Vec;
+use spin::RwLock;
+use wasm3::Module;
+use wasm3::error::Result;
+
+pub mod fs;
+pub mod power;
+pub mod serial;
+pub mod time;
+
+/// A kernel function that Wasm programs can import.
+pub struct HostFunction {
+    pub module: &'static str,
+    pub name: &'static str,
+    /// Links the function under its name. `link_closure` checks its signature against the import.
+    link: fn(&mut Module) -> Result<()>,
+}
+
+impl HostFunction {
+    pub const fn new(
+        module: &'static str,
+        name: &'static str,
+        link: fn(&mut Module) -> Result<()>,
+    ) -> Self {
+        Self { module, name, link }
+    }
+}
+
+/// Makes a `HostFunction` out of a module name, a function name, and a function or closure
+/// that takes a `&CallContext` and the Wasm arguments as a tuple.
+#[macro_export]
+macro_rules! host_function {
+    ($module:expr, $name:expr, $function:expr) => {
+        $crate::host::HostFunction::new($module, $name, |module| {
+            $crate::wasi::link_closure(module, $module, $name, $function)
+        })
+    };
+}
+
+static FUNCTIONS: RwLock<Vec<HostFunction>> = RwLock::new(Vec::new());
+
+/// Registers the kernel's own modules: `ok:serial`, `ok:fs`, `ok:time` and `ok:power`.
+pub fn host_init() {
+    serial::register();
+    fs::register();
+    time::register();
+    power::register();
+}
+
+/// Makes host functions available to every program linked after this,
+/// replacing any that were registered under the same module and name.
+pub fn register(functions: impl IntoIterator<Item = HostFunction>) {
+    let mut registered = FUNCTIONS.write();
+    for function in functions {
+        registered.retain(|old| (old.module, old.name) != (function.module, function.name));
+        registered.push(function);
+    }
+}
+
+/// Links every registered host function that the module imports.
+pub fn link(module: &mut Module) -> Result<()> {
+    for function in FUNCTIONS.read().iter() {
+        ignore_missing((function.link)(module))?;
+    }
+    Ok(())
+}
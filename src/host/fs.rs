@@ -0,0 +1,101 @@
+use crate::errno::Errno;
+use crate::fs::{Descriptor, OpenFlags};
+use crate::host_function;
+use crate::wasi::{Outcome, errno, memory, read_bytes, resolve, write_u32};
+use wasm3::CallContext;
+
+const MODULE: &str = "ok:fs";
+
+pub fn register() {
+    super::register([
+        host_function!(MODULE, "read_file", |cc: &CallContext,
+                                             (
+            fd,
+            path,
+            length,
+            buffer,
+            buffer_length,
+            size,
+        ): (
+            u32,
+            u32,
+            u32,
+            u32,
+            u32,
+            u32
+        )| {
+            errno(read_file(cc, fd, path, length, buffer, buffer_length, size))
+        }),
+        host_function!(MODULE, "write_file", |cc: &CallContext,
+                                              (
+            fd,
+            path,
+            length,
+            buffer,
+            buffer_length,
+        ): (
+            u32,
+            u32,
+            u32,
+            u32,
+            u32
+        )| {
+            errno(write_file(cc, fd, path, length, buffer, buffer_length))
+        }),
+    ]);
+}
+
+/// Reads as much of a file as fits in the buffer and stores its whole size,
+/// so a caller whose buffer was too small can try again. Paths are resolved like WASI's.
+fn read_file(
+    cc: &CallContext,
+    fd: u32,
+    path: u32,
+    length: u32,
+    buffer: u32,
+    buffer_length: u32,
+    size: u32,
+) -> Outcome {
+    let path = resolve(cc, fd, path, length)?;
+    let flags = OpenFlags {
+        read: true,
+        ..OpenFlags::default()
+    };
+    let Descriptor::File(file) = crate::fs::open(path, flags)? else {
+        return Err(Errno::BadF);
+    };
+    let file_size = crate::fs::metadata(&file).size;
+    let memory = unsafe { &mut *memory(cc) };
+    let start = buffer as usize;
+    let end = start
+        .checked_add((buffer_length as usize).min(file_size))
+        .ok_or(Errno::Fault)?;
+    crate::fs::read(&file, memory.get_mut(start..end).ok_or(Errno::Fault)?)?;
+    write_u32(memory, size, file_size as u32)
+}
+
+/// Replaces a file's contents, creating it if it doesn't exist.
+fn write_file(
+    cc: &CallContext,
+    fd: u32,
+    path: u32,
+    length: u32,
+    buffer: u32,
+    buffer_length: u32,
+) -> Outcome {
+    let path = resolve(cc, fd, path, length)?;
+    let flags = OpenFlags {
+        write: true,
+        create: true,
+        truncate: true,
+        ..OpenFlags::default()
+    };
+    let Descriptor::File(file) = crate::fs::open(path, flags)? else {
+        return Err(Errno::BadF);
+    };
+    crate::fs::write(
+        &file,
+        read_bytes(unsafe { &*memory(cc) }, buffer, buffer_length)?,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,20 @@
+use crate::host_function;
+use wasm3::CallContext;
+
+const MODULE: &str = "ok:power";
+
+pub fn register() {
+    super::register([
+        host_function!(MODULE, "shutdown", shutdown),
+        host_function!(MODULE, "reboot", reboot),
+    ]);
+}
+
+/// Powers the machine off with `code` as the exit status.
+fn shutdown(_: &CallContext, code: i32) {
+    crate::power::shutdown(code)
+}
+
+fn reboot(_: &CallContext, (): ()) {
+    crate::power::reboot()
+}
@@ -0,0 +1,46 @@
+use crate::host_function;
+use crate::serial::{serial_recv, serial_send};
+use crate::wasi::{Outcome, errno, memory, read_bytes, write_bytes, write_u32};
+use alloc::vec::Vec;
+use wasm3::CallContext;
+
+const MODULE: &str = "ok:serial";
+
+pub fn register() {
+    super::register([
+        host_function!(
+            MODULE,
+            "write",
+            |cc: &CallContext, (buffer, length): (u32, u32)| errno(write(cc, buffer, length))
+        ),
+        host_function!(MODULE, "read", |cc: &CallContext,
+                                        (buffer, length, count): (
+            u32,
+            u32,
+            u32
+        )| {
+            errno(read(cc, buffer, length, count))
+        }),
+    ]);
+}
+
+/// Sends bytes straight to the serial port, whatever stdout is.
+fn write(cc: &CallContext, buffer: u32, length: u32) -> Outcome {
+    for byte in read_bytes(unsafe { &*memory(cc) }, buffer, length)? {
+        serial_send(*byte);
+    }
+    Ok(())
+}
+
+/// Takes the bytes that have arrived at the serial port, up to `length`, without waiting for more.
+fn read(cc: &CallContext, buffer: u32, length: u32, count: u32) -> Outcome {
+    let mut bytes = Vec::new();
+    while bytes.len() < length as usize
+        && let Some(byte) = serial_recv()
+    {
+        bytes.push(byte);
+    }
+    let memory = unsafe { &mut *memory(cc) };
+    write_bytes(memory, buffer, &bytes)?;
+    write_u32(memory, count, bytes.len() as u32)
+}
@@ -0,0 +1,29 @@
+use crate::host_function;
+use wasm3::CallContext;
+
+const MODULE: &str = "ok:time";
+
+pub fn register() {
+    super::register([
+        host_function!(MODULE, "monotonic", |_: &CallContext, ()| {
+            crate::time::monotonic()
+        }),
+        host_function!(MODULE, "realtime", |_: &CallContext, ()| {
+            crate::time::realtime()
+        }),
+        host_function!(MODULE, "resolution", |_: &CallContext, ()| {
+            crate::time::resolution()
+        }),
+        host_function!(MODULE, "sleep", |_: &CallContext, nanoseconds: u64| {
+            sleep(nanoseconds)
+        }),
+    ]);
+}
+
+/// Lets other threads run until `nanoseconds` have passed.
+fn sleep(nanoseconds: u64) {
+    let deadline = crate::time::monotonic().saturating_add(nanoseconds);
+    while crate::time::monotonic() < deadline {
+        crate::sched::yield_now();
+    }
+}
@@ -1,2 +1,4 @@
 #[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/irq.rs")]
 pub mod arch;
+#[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/timer.rs")]
+pub mod timer;
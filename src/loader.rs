@@ -0,0 +1,367 @@
+use crate::boot::{self, BootModule};
+use crate::fs::{Descriptor, OpenFlags};
+use crate::wasi::Environ;
+use crate::{info_println, println, return_if};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ffi::{CStr, c_char};
+use core::mem::MaybeUninit;
+use spin::{Mutex, Once};
+use wasm3::error::{Error, Trap};
+use wasm3::{Environment, Module, Runtime};
+use wasm3_sys::{IM3Runtime, M3ErrorInfo, m3_GetBacktrace, m3_GetErrorInfo, m3_GetFunctionName};
+
+pub mod binary;
+pub mod memory;
+pub mod names;
+
+use names::Names;
+
+/// Module strings of the boot modules, keyed by file path.
+static MANIFESTS: Mutex<BTreeMap<String, &'static str>> = Mutex::new(BTreeMap::new());
+
+/// The init program's path. Only init gets the command line's `arg=` and `env=` options.
+static INIT: Once<String> = Once::new();
+
+/// Strips the Limine volume prefix (`boot():`, `hdd(1:2):` ...) from a module path.
+fn module_name(module: &BootModule) -> String {
+    let path = String::from_utf8_lossy(module.path);
+    match path.find("):") {
+        Some(index) => path[index + 2..].to_string(),
+        None => path.to_string(),
+    }
+}
+
+/// Whether a boot module is the initrd, rather than a file of its own.
+fn is_initrd(module: &BootModule) -> bool {
+    let manifest = core::str::from_utf8(module.string).unwrap_or("");
+    crate::cmdline::options(manifest).any(|(key, _)| key == "initrd")
+}
+
+/// Returns the archive to unpack into the root file system, if one was loaded.
+pub fn initrd() -> Option<&'static [u8]> {
+    boot::modules()
+        .into_iter()
+        .find(is_initrd)
+        .map(|module| module.data)
+}
+
+/// Registers every boot module as a file and returns the path of the init program.
+///
+/// # Safety
+///
+/// The init program is the command line's `init=`, else the module whose string has an `init` option,
+/// else the first module.
+/// The rest of the module string is its manifest, with `arg=` and `env=` options for the program.
+pub fn load_boot_modules() -> Option<String> {
+    let mut manifests = MANIFESTS.lock();
+    let mut init = None;
+    for module in boot::modules()
+        .into_iter()
+        .filter(|module| !is_initrd(module))
+    {
+        let name = module_name(&module);
+        let manifest = core::str::from_utf8(module.string).unwrap_or("");
+        info_println!("module {name} ({} bytes)", module.data.len());
+        crate::fs::create(&name, module.data);
+        if crate::cmdline::options(manifest).any(|(key, _)| key == "init") || init.is_none() {
+            init = Some(name.clone());
+        }
+        manifests.insert(name, manifest);
+    }
+    let init = crate::cmdline::get().init.map(String::from).or(init);
+    if let Some(path) = &init {
+        INIT.call_once(|| path.clone());
+    }
+    init
+}
+
+/// Returns the boot modules whose manifest has a `service` option, to be started alongside init.
+pub fn services() -> Vec<String> {
+    MANIFESTS
+        .lock()
+        .iter()
+        .filter(|(_, manifest)| crate::cmdline::options(manifest).any(|(key, _)| key == "service"))
+        .map(|(path, _)| path.clone())
+        .collect()
+}
+
+/// Builds argv and envp for a program from its manifest, and for init from the kernel command line.
+fn environ(path: &str) -> Environ {
+    let cmdline = crate::cmdline::get();
+    let manifest = MANIFESTS.lock().get(path).copied().unwrap_or("");
+    let mut args = Vec::from([path.to_string()]);
+    args.extend(crate::cmdline::values(manifest, "arg").map(String::from));
+    let mut vars: Vec<String> = crate::cmdline::values(manifest, "env")
+        .map(String::from)
+        .collect();
+    if INIT.get().is_some_and(|init| init == path) {
+        args.extend(cmdline.args().map(String::from));
+        vars.extend(cmdline.vars().map(String::from));
+    }
+    Environ { args, vars }
+}
+
+/// Returns the directories a program gets preopened: its manifest's `preopen=` options, or `/`.
+fn preopens(path: &str) -> Vec<String> {
+    let manifest = MANIFESTS.lock().get(path).copied().unwrap_or("");
+    let preopens: Vec<String> = crate::cmdline::values(manifest, "preopen")
+        .map(String::from)
+        .collect();
+    if preopens.is_empty() {
+        Vec::from([String::from("/")])
+    } else {
+        preopens
+    }
+}
+
+/// Returns the last value a program's manifest gives an option.
+fn manifest_value(path: &str, key: &'static str) -> Option<&'static str> {
+    let manifest = MANIFESTS.lock().get(path).copied().unwrap_or("");
+    crate::cmdline::values(manifest, key).last()
+}
+
+fn read_file(path: &str) -> Option<Vec<u8>> {
+    let Descriptor::File(file) = crate::fs::open(
+        path.to_string(),
+        OpenFlags {
+            read: true,
+            ..OpenFlags::default()
+        },
+    )
+    .ok()?
+    else {
+        return None;
+    };
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 4096];
+    while let Ok(length @ 1..) = crate::fs::read(&file, &mut buffer) {
+        data.extend_from_slice(&buffer[..length]);
+    }
+    (!data.is_empty()).then_some(data)
+}
+
+/// Exit status of a program that trapped, like a Unix program killed by SIGABRT.
+const TRAP_STATUS: i32 = 134;
+/// Exit status of a program that couldn't be started, like a Unix shell's.
+const NOT_EXECUTABLE_STATUS: i32 = 126;
+/// What wasm3 puts in `lastFrame` when it couldn't record the whole backtrace.
+const BACKTRACE_TRUNCATED: usize = usize::MAX;
+
+/// How each trap wasm3 raises is described, in the words of the spec's tests.
+const TRAPS: [(Trap, &str); 11] = [
+    (Trap::OutOfBoundsMemoryAccess, "out of bounds memory access"),
+    (Trap::DivisionByZero, "integer divide by zero"),
+    (Trap::IntegerOverflow, "integer overflow"),
+    (Trap::IntegerConversion, "invalid conversion to integer"),
+    (
+        Trap::IndirectCallTypeMismatch,
+        "indirect call type mismatch",
+    ),
+    (Trap::TableIndexOutOfRange, "undefined element"),
+    (Trap::TableElementIsNull, "uninitialized element"),
+    (Trap::Exit, "exit"),
+    (Trap::Abort, "abort"),
+    (Trap::Unreachable, "unreachable"),
+    (Trap::StackOverflow, "stack overflow"),
+];
+
+/// A frame of a trap's backtrace, innermost first.
+struct Frame {
+    /// The name wasm3 knows the function by, if it has one.
+    function: Option<String>,
+    /// Where the frame was in the module, in bytes from its start.
+    offset: u32,
+}
+
+/// What wasm3 recorded in the runtime about the last trap.
+struct Trace {
+    message: Option<String>,
+    frames: Vec<Frame>,
+    /// wasm3 ran out of memory partway through recording the frames.
+    truncated: bool,
+}
+
+fn c_string(pointer: *const c_char) -> Option<String> {
+    return_if!(pointer.is_null(), None);
+    Some(
+        unsafe { CStr::from_ptr(pointer) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// What wasm3 keeps right before a runtime's linear memory, as `M3MemoryHeader` in `m3_env.h`.
+#[repr(C)]
+struct MemoryHeader {
+    runtime: IM3Runtime,
+    max_stack: *mut core::ffi::c_void,
+    length: usize,
+}
+
+/// Finds wasm3's pointer to a runtime through the header in front of its linear memory,
+/// the way wasm3's own instructions do, since wasm3-rs doesn't hand it out.
+fn raw_runtime(runtime: &Runtime) -> Option<IM3Runtime> {
+    let memory = runtime.memory() as *const u8;
+    return_if!(memory.is_null(), None);
+    let header = unsafe { &*memory.cast::<MemoryHeader>().sub(1) };
+    (!header.runtime.is_null()).then_some(header.runtime)
+}
+
+/// Reads the error info and backtrace of the trap the runtime last stopped with.
+/// Frames are only recorded if wasm3 is built with `d_m3RecordBacktraces`; without it the list is empty.
+fn trace(runtime: &Runtime) -> Trace {
+    let mut trace = Trace {
+        message: None,
+        frames: Vec::new(),
+        truncated: false,
+    };
+    let Some(raw) = raw_runtime(runtime) else {
+        return trace;
+    };
+    let mut info = MaybeUninit::<M3ErrorInfo>::zeroed();
+    let info = unsafe {
+        m3_GetErrorInfo(raw, info.as_mut_ptr());
+        info.assume_init()
+    };
+    trace.message = c_string(info.message).filter(|message| !message.is_empty());
+    let backtrace = unsafe { m3_GetBacktrace(raw) };
+    return_if!(backtrace.is_null(), trace);
+    let backtrace = unsafe { &*backtrace };
+    trace.truncated = backtrace.lastFrame as usize == BACKTRACE_TRUNCATED;
+    let mut frame = backtrace.frames;
+    while !frame.is_null() && frame as usize != BACKTRACE_TRUNCATED {
+        let current = unsafe { &*frame };
+        let function = if current.function.is_null() {
+            None
+        } else {
+            c_string(unsafe { m3_GetFunctionName(current.function) })
+        };
+        trace.frames.push(Frame {
+            function,
+            offset: current.moduleOffset,
+        });
+        frame = current.next;
+    }
+    trace
+}
+
+/// Prints which process trapped, how, and the frames it was in, symbolized with the module's names.
+/// Without a backtrace, the report stops at the entry function.
+fn report_trap(path: &str, names: &Names, entry: &str, error: Error, trace: Trace) {
+    let pid = crate::sched::current_process().unwrap_or(0);
+    let kind = match error {
+        Error::Wasm3(error) => TRAPS
+            .iter()
+            .find(|(trap, _)| error.is_trap(*trap))
+            .map_or("runtime error", |(_, kind)| kind),
+        _ => "host error",
+    };
+    println!("[{pid}] {path}: {kind} ({error})");
+    if let Some(message) = &trace.message {
+        println!("    {message}");
+    }
+    if trace.frames.is_empty() {
+        println!("    #0 {entry}");
+    }
+    for (number, frame) in trace.frames.iter().enumerate() {
+        let function = match names.function_at(frame.offset) {
+            Some(index) => names.describe(index),
+            None => frame.function.clone().unwrap_or_else(|| String::from("?")),
+        };
+        println!("    #{number} {function} at {:#x}", frame.offset);
+    }
+    if trace.truncated {
+        println!("    ...");
+    }
+}
+
+/// Runs the `_start` export of the Wasm program at `path` to completion and returns its exit code.
+///
+/// # Safety
+///
+/// Runs on the thread of the process it belongs to, which owns the runtime.
+/// The manifest's `stack=`, `memory=` and `pages=` options set the runtime's stack size, how much
+/// wasm3 may allocate for the process, and how many pages its linear memory may grow to.
+/// `proc_exit` records the status and stops the program with the exit trap. Any other trap is
+/// reported, even after a status was recorded.
+pub fn run(path: &str) -> i32 {
+    let Some(bytes) = read_file(path) else {
+        println!("{path}: no such program");
+        return 127;
+    };
+    let environ = environ(path);
+    info_println!("exec {}", environ.args.join(" "));
+    let stack_size = match manifest_value(path, "stack").and_then(crate::cmdline::parse_size) {
+        Some(size) => u32::try_from(size).unwrap_or_else(|_| {
+            println!("{path}: stack={size} is out of range, ignoring it");
+            crate::cmdline::get().stack_size
+        }),
+        None => crate::cmdline::get().stack_size,
+    };
+    let memory_limit = manifest_value(path, "memory").and_then(crate::cmdline::parse_size);
+    crate::process::with_current(|process| process.memory_limit = memory_limit);
+    let bytes = match manifest_value(path, "pages").and_then(|pages| pages.parse().ok()) {
+        Some(max_pages) => match memory::limit_pages(bytes, max_pages) {
+            Ok(bytes) => bytes,
+            Err(message) => {
+                println!("{path}: {message}");
+                return NOT_EXECUTABLE_STATUS;
+            }
+        },
+        None => bytes,
+    };
+    // Parsed after `limit_pages`, so offsets line up with the bytes wasm3 runs.
+    let names = Names::parse(&bytes);
+    let env = Environment::new().expect("Unable to create environment");
+    let rt = match env.create_runtime(stack_size) {
+        Ok(rt) => rt,
+        Err(error) => {
+            println!("{path}: can't make a runtime with a {stack_size}-byte stack ({error})");
+            return NOT_EXECUTABLE_STATUS;
+        }
+    };
+    let mut module = match Module::parse(&env, bytes).and_then(|module| rt.load_module(module)) {
+        Ok(module) => module,
+        Err(error) => {
+            println!("{path}: not a valid module ({error})");
+            return NOT_EXECUTABLE_STATUS;
+        }
+    };
+    if let Err(error) = module.link_wasi() {
+        println!("{path}: can't link its WASI imports ({error})");
+        return NOT_EXECUTABLE_STATUS;
+    }
+    crate::process::preopen(&preopens(path));
+    if let Err(error) = crate::wasi::link(&mut module, Arc::new(environ)) {
+        println!("{path}: can't link its WASI imports ({error})");
+        return NOT_EXECUTABLE_STATUS;
+    }
+    if let Err(error) = crate::host::link(&mut module) {
+        println!("{path}: can't link its ok imports ({error})");
+        return NOT_EXECUTABLE_STATUS;
+    }
+    let Ok(func) = module.find_function::<(), ()>("_start") else {
+        println!("{path}: no _start function");
+        return NOT_EXECUTABLE_STATUS;
+    };
+    let entry = names.frame(func.name());
+    crate::process::with_current(|process| process.entry = Some(entry.clone()));
+    let budget = manifest_value(path, "budget").and_then(crate::cmdline::parse_duration);
+    crate::sched::set_budget(budget);
+    let result = crate::sched::run_guest(|| func.call());
+    match result {
+        Ok(()) => 0,
+        Err(Error::Wasm3(error)) if error.is_trap(Trap::Exit) => {
+            crate::process::with_current(|process| process.exit_code)
+                .flatten()
+                .unwrap_or(0)
+        }
+        Err(error) => {
+            report_trap(path, &names, &entry, error, trace(&rt));
+            TRAP_STATUS
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The magic number and version every module starts with.
+pub const HEADER: &[u8] = b"\0asm\x01\0\0\0";
+
+pub const SECTION_CUSTOM: u8 = 0;
+pub const SECTION_IMPORT: u8 = 2;
+pub const SECTION_MEMORY: u8 = 5;
+pub const SECTION_EXPORT: u8 = 7;
+pub const SECTION_CODE: u8 = 10;
+
+/// Reads the encodings the binary format is made of.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Starts reading after the header, if `module` has the one for version 1.
+    pub fn sections(module: &'a [u8]) -> Option<Reader<'a>> {
+        Some(Reader {
+            bytes: module.strip_prefix(HEADER)?,
+        })
+    }
+
+    /// Returns what hasn't been read yet.
+    pub fn rest(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn byte(&mut self) -> Option<u8> {
+        let (byte, rest) = self.bytes.split_first()?;
+        self.bytes = rest;
+        Some(*byte)
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(..length)?;
+        self.bytes = &self.bytes[length..];
+        Some(bytes)
+    }
+
+    /// Reads an unsigned LEB128 number.
+    pub fn u32(&mut self) -> Option<u32> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7F) as u32).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    pub fn name(&mut self) -> Option<String> {
+        let length = self.u32()? as usize;
+        Some(String::from_utf8_lossy(self.bytes(length)?).into())
+    }
+
+    /// Splits off the next `length`-prefixed part, like a section or subsection.
+    pub fn part(&mut self) -> Option<Reader<'a>> {
+        let length = self.u32()? as usize;
+        Some(Reader {
+            bytes: self.bytes(length)?,
+        })
+    }
+}
+
+/// Appends an unsigned LEB128 number.
+pub fn write_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
@@ -0,0 +1,76 @@
+use super::binary::{Reader, SECTION_MEMORY, write_u32};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Limits: there's a maximum page count.
+const LIMITS_MAX: u8 = 1;
+
+/// Caps the maximum size of every memory a module defines at `max_pages`,
+/// so that `memory.grow` fails past it rather than eating the kernel heap.
+///
+/// # Safety
+///
+/// Fails if a memory needs more than `max_pages` to begin with. Shared and 64-bit memories,
+/// and modules the kernel can't read, are passed through unchanged for wasm3 to deal with.
+pub fn limit_pages(module: Vec<u8>, max_pages: u32) -> Result<Vec<u8>, String> {
+    let Some(mut reader) = Reader::sections(&module) else {
+        return Ok(module);
+    };
+    loop {
+        let start = module.len() - reader.rest().len();
+        let (Some(id), Some(mut section)) = (reader.byte(), reader.part()) else {
+            return Ok(module);
+        };
+        if id != SECTION_MEMORY {
+            continue;
+        }
+        let Some(payload) = limit_section(&mut section, max_pages)? else {
+            return Ok(module);
+        };
+        let end = module.len() - reader.rest().len();
+        let mut limited = Vec::with_capacity(module.len() + 8);
+        limited.extend_from_slice(&module[..start]);
+        limited.push(SECTION_MEMORY);
+        write_u32(&mut limited, payload.len() as u32);
+        limited.extend_from_slice(&payload);
+        limited.extend_from_slice(&module[end..]);
+        return Ok(limited);
+    }
+}
+
+/// Re-encodes a memory section with every maximum at most `max_pages`.
+fn limit_section(section: &mut Reader, max_pages: u32) -> Result<Option<Vec<u8>>, String> {
+    let mut payload = Vec::new();
+    let Some(count) = section.u32() else {
+        return Ok(None);
+    };
+    write_u32(&mut payload, count);
+    for _ in 0..count {
+        let Some(flags) = section.byte() else {
+            return Ok(None);
+        };
+        if flags & !LIMITS_MAX != 0 {
+            return Ok(None);
+        }
+        let Some(min) = section.u32() else {
+            return Ok(None);
+        };
+        let max = match flags & LIMITS_MAX {
+            0 => max_pages,
+            _ => match section.u32() {
+                Some(max) => max.min(max_pages),
+                None => return Ok(None),
+            },
+        };
+        if min > max_pages {
+            return Err(format!(
+                "needs {min} pages of memory, but may only have {max_pages}"
+            ));
+        }
+        payload.push(LIMITS_MAX);
+        write_u32(&mut payload, min);
+        write_u32(&mut payload, max);
+    }
+    Ok(Some(payload))
+}
@@ -0,0 +1,175 @@
+use super::binary::{Reader, SECTION_CODE, SECTION_CUSTOM, SECTION_EXPORT, SECTION_IMPORT};
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// The function names from a module's `name` custom section, for symbolizing trap reports.
+/// DWARF isn't read, so frames don't come with source lines.
+#[derive(Default)]
+pub struct Names {
+    /// The name the module gave itself, if any.
+    pub module: Option<String>,
+    functions: BTreeMap<u32, String>,
+    /// Exported functions, by export name.
+    exports: BTreeMap<String, u32>,
+    /// Imported functions come first in the index space, before any with a body.
+    imported_functions: u32,
+    /// Where each function body starts and ends, in bytes from the start of the module.
+    bodies: Vec<(usize, usize)>,
+}
+
+const SUBSECTION_MODULE: u8 = 0;
+const SUBSECTION_FUNCTIONS: u8 = 1;
+const EXTERNAL_FUNCTION: u8 = 0;
+const EXTERNAL_TABLE: u8 = 1;
+const EXTERNAL_MEMORY: u8 = 2;
+const EXTERNAL_GLOBAL: u8 = 3;
+const EXTERNAL_TAG: u8 = 4;
+/// A table or memory's limits have a maximum.
+const LIMITS_MAXIMUM: u8 = 1;
+
+impl Names {
+    /// Collects the names in a module, skipping any part that's malformed.
+    /// Modules built without debug info have no `name` section, and get no names.
+    pub fn parse(module: &[u8]) -> Names {
+        let mut names = Names::default();
+        let Some(mut reader) = Reader::sections(module) else {
+            return names;
+        };
+        while let (Some(id), Some(mut section)) = (reader.byte(), reader.part()) {
+            match id {
+                SECTION_CUSTOM if section.name().as_deref() == Some("name") => {
+                    names.parse_name_section(section);
+                }
+                SECTION_IMPORT => {
+                    names.parse_import_section(section);
+                }
+                SECTION_EXPORT => {
+                    names.parse_export_section(section);
+                }
+                SECTION_CODE => {
+                    names.parse_code_section(module, section);
+                }
+                _ => {}
+            }
+        }
+        names
+    }
+
+    fn parse_name_section(&mut self, mut section: Reader) {
+        while let (Some(id), Some(mut subsection)) = (section.byte(), section.part()) {
+            match id {
+                SUBSECTION_MODULE => self.module = subsection.name(),
+                SUBSECTION_FUNCTIONS => {
+                    let count = subsection.u32().unwrap_or(0);
+                    for _ in 0..count {
+                        let (Some(index), Some(name)) = (subsection.u32(), subsection.name())
+                        else {
+                            break;
+                        };
+                        self.functions.insert(index, name);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Counts the imported functions, skipping over the other kinds of import.
+    fn parse_import_section(&mut self, mut section: Reader) -> Option<()> {
+        for _ in 0..section.u32()? {
+            section.name()?;
+            section.name()?;
+            match section.byte()? {
+                EXTERNAL_FUNCTION => {
+                    section.u32()?;
+                    self.imported_functions += 1;
+                }
+                EXTERNAL_TABLE => {
+                    section.byte()?;
+                    skip_limits(&mut section)?;
+                }
+                EXTERNAL_MEMORY => skip_limits(&mut section)?,
+                EXTERNAL_GLOBAL => {
+                    section.bytes(2)?;
+                }
+                EXTERNAL_TAG => {
+                    section.byte()?;
+                    section.u32()?;
+                }
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+
+    fn parse_export_section(&mut self, mut section: Reader) -> Option<()> {
+        for _ in 0..section.u32()? {
+            let name = section.name()?;
+            let kind = section.byte()?;
+            let index = section.u32()?;
+            if kind == EXTERNAL_FUNCTION {
+                self.exports.insert(name, index);
+            }
+        }
+        Some(())
+    }
+
+    fn parse_code_section(&mut self, module: &[u8], mut section: Reader) -> Option<()> {
+        for _ in 0..section.u32()? {
+            let size = section.u32()? as usize;
+            let start = section.rest().as_ptr() as usize - module.as_ptr() as usize;
+            section.bytes(size)?;
+            self.bodies.push((start, start + size));
+        }
+        Some(())
+    }
+
+    /// Returns the index of the function whose body holds `offset`, in bytes from the start of the module.
+    pub fn function_at(&self, offset: u32) -> Option<u32> {
+        let offset = offset as usize;
+        let body = self.bodies.partition_point(|(start, _)| *start <= offset);
+        let (_, end) = self.bodies.get(body.checked_sub(1)?)?;
+        (offset < *end).then_some(self.imported_functions + body as u32 - 1)
+    }
+
+    /// Describes a function as a frame, like `func[12] <main> in hello`.
+    /// Functions the `name` section leaves out go by their export name, if they have one.
+    pub fn describe(&self, index: u32) -> String {
+        let export = self
+            .exports
+            .iter()
+            .find(|(_, export)| **export == index)
+            .map(|(name, _)| name.as_str());
+        self.format(index, export)
+    }
+
+    /// Describes the function behind an export as a frame.
+    pub fn frame(&self, export: &str) -> String {
+        match self.exports.get(export) {
+            Some(index) => self.format(*index, Some(export)),
+            None => format!("<{export}>"),
+        }
+    }
+
+    fn format(&self, index: u32, export: Option<&str>) -> String {
+        let function = match self.functions.get(&index).map(String::as_str).or(export) {
+            Some(name) => format!("func[{index}] <{name}>"),
+            None => format!("func[{index}]"),
+        };
+        match &self.module {
+            Some(module) => format!("{function} in {module}"),
+            None => function,
+        }
+    }
+}
+
+fn skip_limits(section: &mut Reader) -> Option<()> {
+    let flags = section.byte()?;
+    section.u32()?;
+    if flags & LIMITS_MAXIMUM != 0 {
+        section.u32()?;
+    }
+    Some(())
+}
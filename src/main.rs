@@ -8,61 +8,101 @@
 #![no_std]
 #![no_main]
 
+mod acpi;
+#[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/boot.rs")]
+mod boot;
+mod cmdline;
 #[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/cpu.rs")]
 mod cpu;
+mod errno;
 mod fs;
 mod helper;
+mod host;
 mod irq;
+mod loader;
 mod mm;
+mod power;
+mod process;
+mod sched;
 #[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/serial.rs")]
 mod serial;
+mod time;
+mod wasi;
 mod clib;
 
 extern crate alloc;
 
 use core::panic::PanicInfo;
 
-use wasm3::Environment;
-use wasm3::Module;
-
-//static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
-
 #[unsafe(no_mangle)]
 extern "C" fn _start() -> ! {
     serial::serial_init();
-    println!("ok");
+    info_println!("ok");
+    for (key, value) in cmdline::get().rejected() {
+        println!("cmdline: {key}={value} is out of range, ignoring it");
+    }
     mm::arch::mm_init();
-    println!("mm");
+    info_println!("mm");
     irq::arch::irq_init();
-    println!("irq");
+    info_println!("irq");
     cpu::cpu_init();
-    println!("cpu");
+    info_println!("cpu");
+    acpi::acpi_init();
+    info_println!("acpi");
+    sched::sched_init();
+    info_println!("sched");
+    time::time_init();
+    info_println!("time");
+    irq::timer::timer_init();
+    info_println!("timer");
+    fs::fs_init(loader::initrd());
+    info_println!("fs");
+    host::host_init();
+    info_println!("host");
+    let init = loader::load_boot_modules();
+    info_println!("modules");
     // Language runtime below
-    let env = Environment::new().expect("Unable to create environment");
-    let rt = env
-        .create_runtime(1024 * 64)
-        .expect("Unable to create runtime");
-    let module = Module::parse(&env, &include_bytes!("wasm_print.wasm")[..])
-        .expect("Unable to parse module");
-    let mut module = rt.load_module(module).expect("Unable to load module");
-    module.link_wasi().expect("Failed to link wasi");
-    let func = module
-        .find_function::<(), ()>("_start")
-        .expect("Unable to find function");
-    func.call().unwrap();
-    // Language runtime above
-    println!("done!");
-    loop {
+    let init_pid = match &init {
+        Some(path) => Some(process::spawn(path.clone())),
+        None => {
+            println!("no init module");
+            None
+        }
+    };
+    for path in loader::services() {
+        if Some(&path) != init.as_ref() {
+            process::spawn(path);
+        }
+    }
+    while process::running() > 0 {
+        sched::yield_now();
         unsafe {
             #[cfg(target_arch = "x86_64")]
             x86::halt();
         }
     }
+    // Language runtime above
+    info_println!("done!");
+    let code = init_pid.and_then(process::exit_code).unwrap_or(0);
+    power::shutdown(code)
 }
 
 #[panic_handler]
 fn rust_panic(info: &PanicInfo) -> ! {
     println!("{info}");
+    // Printing from inside the closure, because the heap may be what panicked.
+    process::try_with_current(|process| {
+        println!("while running {}", process.name);
+        if let Some((module, name)) = process.host_call {
+            println!("    #0 {module}.{name} (host function)");
+        }
+        if let Some(entry) = &process.entry {
+            match process.host_call {
+                Some(_) => println!("    ... called from Wasm entered at {entry}"),
+                None => println!("    #0 {entry}"),
+            }
+        }
+    });
     hcf()
 }
 
@@ -0,0 +1,16 @@
+use crate::info_println;
+
+#[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/power.rs")]
+pub mod arch;
+
+/// Turns the machine off, handing `code` to the emulator if it's listening for one.
+pub fn shutdown(code: i32) -> ! {
+    info_println!("shutting down with status {code}");
+    arch::shutdown(code)
+}
+
+/// Restarts the machine.
+pub fn reboot() -> ! {
+    info_println!("rebooting");
+    arch::reboot()
+}
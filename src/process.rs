@@ -0,0 +1,191 @@
+use crate::fs::{Descriptor, FdTable};
+use crate::{info_println, println, sched};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use spin::Mutex;
+
+pub type Pid = usize;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum State {
+    Running,
+    Exited,
+}
+
+pub struct Process {
+    pub name: String,
+    pub state: State,
+    /// Set by `proc_exit` just before it traps, so before the process has finished exiting.
+    pub exit_code: Option<i32>,
+    pub fds: FdTable,
+    /// The directories a WASI program is given, by fd and the path it sees them as.
+    pub preopens: BTreeMap<i32, String>,
+    /// Where the program was entered, symbolized, once it's running.
+    pub entry: Option<String>,
+    /// The host function the program is in, by module and name, for the panic handler.
+    pub host_call: Option<(&'static str, &'static str)>,
+    /// Bytes the process is using, and how many it may: its kernel stack, what wasm3 allocates
+    /// for it through `malloc`, and tmpfs file data it has written.
+    pub memory: usize,
+    pub memory_limit: Option<usize>,
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+static NEXT_PID: Mutex<Pid> = Mutex::new(1);
+
+/// Starts the Wasm program at `path` as a new process with its own thread.
+pub fn spawn(path: String) -> Pid {
+    let pid = {
+        let mut next_pid = NEXT_PID.lock();
+        let pid = *next_pid;
+        *next_pid += 1;
+        pid
+    };
+    PROCESSES.lock().insert(
+        pid,
+        Process {
+            name: path.clone(),
+            state: State::Running,
+            exit_code: None,
+            fds: FdTable::new(),
+            preopens: BTreeMap::new(),
+            entry: None,
+            host_call: None,
+            memory: sched::STACK_SIZE,
+            memory_limit: None,
+        },
+    );
+    sched::spawn(
+        Some(pid),
+        Box::new(move || {
+            let code = crate::loader::run(&path);
+            exit(code)
+        }),
+    );
+    pid
+}
+
+/// Runs `f` on the process the current thread belongs to.
+pub fn with_current<T>(f: impl FnOnce(&mut Process) -> T) -> Option<T> {
+    let pid = sched::current_process()?;
+    PROCESSES.lock().get_mut(&pid).map(f)
+}
+
+/// Like `with_current`, but gives up instead of waiting for a lock.
+///
+/// # Safety
+///
+/// For the panic handler, which may have interrupted whoever holds one.
+pub fn try_with_current<T>(f: impl FnOnce(&Process) -> T) -> Option<T> {
+    let pid = sched::try_current_process()?;
+    PROCESSES.try_lock()?.get(&pid).map(f)
+}
+
+/// Looks up a file descriptor in the current process's table.
+pub fn descriptor(fd: i32) -> Option<Descriptor> {
+    with_current(|process| process.fds.get(fd)).flatten()
+}
+
+/// Opens the current process's preopened directories at fds 3 and up.
+///
+/// # Safety
+///
+/// Replaces every descriptor past stderr, including the ones wasm3 opens while linking WASI,
+/// so a program can't reach anything but what it was given. Missing directories are skipped.
+pub fn preopen(paths: &[String]) {
+    let mut fds = FdTable::new();
+    let mut preopens = BTreeMap::new();
+    for path in paths {
+        let Ok(directory) = crate::fs::open_directory(path) else {
+            info_println!("preopen {path}: no such directory");
+            continue;
+        };
+        let Ok(fd) = fds.insert(directory) else {
+            info_println!("preopen {path}: too many open files");
+            break;
+        };
+        preopens.insert(fd, path.clone());
+    }
+    let old_fds = with_current(|process| {
+        process.preopens = preopens;
+        core::mem::replace(&mut process.fds, fds)
+    });
+    drop(old_fds);
+}
+
+/// Counts `size` more bytes of memory against a process, unless that would take it over its limit.
+/// Kernel threads don't belong to a process and have no limit.
+pub fn reserve_memory(pid: Option<Pid>, size: usize) -> bool {
+    let Some(pid) = pid else {
+        return true;
+    };
+    let mut processes = PROCESSES.lock();
+    let Some(process) = processes.get_mut(&pid) else {
+        return true;
+    };
+    let memory = process.memory.saturating_add(size);
+    if process.memory_limit.is_some_and(|limit| memory > limit) {
+        return false;
+    }
+    process.memory = memory;
+    true
+}
+
+/// Gives back memory counted by `reserve_memory`.
+pub fn release_memory(pid: Option<Pid>, size: usize) {
+    if let Some(pid) = pid
+        && let Some(process) = PROCESSES.lock().get_mut(&pid)
+    {
+        process.memory = process.memory.saturating_sub(size);
+    }
+}
+
+/// Returns how many processes haven't exited yet.
+pub fn running() -> usize {
+    PROCESSES
+        .lock()
+        .values()
+        .filter(|process| process.state == State::Running)
+        .count()
+}
+
+/// Returns the exit code of a process, once it has exited.
+pub fn exit_code(pid: Pid) -> Option<i32> {
+    PROCESSES.lock().get(&pid)?.exit_code
+}
+
+/// Exit status of a process that was killed, like a Unix program killed by SIGKILL.
+const KILLED_STATUS: i32 = 137;
+
+/// Ends the current process because it ran out of CPU budget. The scheduler sends it here.
+pub fn kill() -> ! {
+    let pid = sched::current_process().expect("kernel thread was killed");
+    with_current(|process| {
+        println!("[{pid}] {}: out of cpu budget, killed", process.name);
+        if let Some(entry) = &process.entry {
+            println!("    #0 {entry}");
+        }
+    });
+    exit(KILLED_STATUS)
+}
+
+/// Records the exit code of the current process, closes its files and ends its thread.
+pub fn exit(code: i32) -> ! {
+    let pid = sched::current_process().expect("kernel thread called process::exit");
+    let (name, fds) = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).unwrap();
+        process.state = State::Exited;
+        process.exit_code = Some(code);
+        (process.name.clone(), core::mem::take(&mut process.fds))
+    };
+    drop(fds);
+    let cpu_time = sched::cpu_time(true);
+    info_println!(
+        "[{pid}] {name} exited with status {code} after {}.{:03}s of cpu time",
+        cpu_time / 1_000_000_000,
+        cpu_time / 1_000_000 % 1000
+    );
+    sched::exit()
+}
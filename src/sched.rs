@@ -0,0 +1,276 @@
+use crate::process::Pid;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+#[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/sched.rs")]
+pub mod arch;
+
+use arch::{Context, without_interrupts};
+
+pub type Tid = usize;
+
+pub const STACK_SIZE: usize = 1024 * 256;
+
+#[derive(PartialEq, Eq)]
+enum State {
+    Running,
+    Ready,
+    Exited,
+}
+
+struct Thread {
+    context: Context,
+    state: State,
+    process: Option<Pid>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// Nanoseconds spent running, up to the last time it was switched away from.
+    cpu_time: u64,
+    /// How much CPU time the thread may use before it's killed.
+    budget: Option<u64>,
+    /// `KERNEL_DEPTH` while the thread isn't running.
+    kernel_depth: usize,
+    /// The thread ran out of budget in guest code, and gets sent to `killed_entry` next time it runs.
+    killed: bool,
+    // The boot thread runs on the stack Limine gave us.
+    stack: Option<Vec<u8>>,
+}
+
+/// # Safety
+///
+/// Only ever locked with interrupts disabled, so the timer can't preempt whoever holds it.
+struct Scheduler {
+    threads: BTreeMap<Tid, Box<Thread>>,
+    ready: VecDeque<Tid>,
+    current: Tid,
+    next_tid: Tid,
+    /// When the current thread was switched to, on the monotonic clock.
+    switched_at: u64,
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    threads: BTreeMap::new(),
+    ready: VecDeque::new(),
+    current: 0,
+    next_tid: 1,
+    switched_at: 0,
+});
+
+/// How many kernel sections the current thread is in. 0 means it's running guest code,
+/// which holds no kernel locks, so it's the only place a thread can safely be killed.
+static KERNEL_DEPTH: AtomicUsize = AtomicUsize::new(1);
+
+/// Marks the current thread as running kernel code until it's dropped.
+pub struct KernelSection(());
+
+impl KernelSection {
+    pub fn enter() -> Self {
+        KERNEL_DEPTH.fetch_add(1, Ordering::Relaxed);
+        KernelSection(())
+    }
+}
+
+impl Drop for KernelSection {
+    fn drop(&mut self) {
+        KERNEL_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs guest code, where the current thread can be killed if it's over budget.
+///
+/// # Safety
+///
+/// Everything `f` calls back into the kernel through must enter a `KernelSection`.
+pub fn run_guest<T>(f: impl FnOnce() -> T) -> T {
+    KERNEL_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    let result = f();
+    KERNEL_DEPTH.fetch_add(1, Ordering::Relaxed);
+    result
+}
+
+/// Limits how many nanoseconds of CPU time the current thread may use in total.
+pub fn set_budget(budget: Option<u64>) {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).unwrap().budget = budget;
+    });
+}
+
+/// Turns the code that's currently running into thread 0.
+pub fn sched_init() {
+    let thread = Box::new(Thread {
+        context: Context::default(),
+        state: State::Running,
+        process: None,
+        entry: None,
+        cpu_time: 0,
+        budget: None,
+        kernel_depth: 1,
+        killed: false,
+        stack: None,
+    });
+    without_interrupts(|| SCHEDULER.lock().threads.insert(0, thread));
+}
+
+/// Creates a thread that runs `entry` on its own stack once it's scheduled.
+pub fn spawn(process: Option<Pid>, entry: Box<dyn FnOnce() + Send>) -> Tid {
+    let mut stack = vec![0u8; STACK_SIZE];
+    let context = Context::new(&mut stack, thread_entry);
+    let thread = Box::new(Thread {
+        context,
+        state: State::Ready,
+        process,
+        entry: Some(entry),
+        cpu_time: 0,
+        budget: None,
+        kernel_depth: 1,
+        killed: false,
+        stack: Some(stack),
+    });
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let tid = scheduler.next_tid;
+        scheduler.next_tid += 1;
+        scheduler.threads.insert(tid, thread);
+        scheduler.ready.push_back(tid);
+        tid
+    })
+}
+
+extern "C" fn thread_entry() -> ! {
+    let entry = {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).unwrap().entry.take()
+    };
+    // Threads are first switched to with interrupts disabled, and there's no caller to restore them.
+    arch::enable_interrupts();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+/// Where a thread that ran out of budget goes instead of back to what it was doing.
+extern "C" fn killed_entry() -> ! {
+    arch::enable_interrupts();
+    crate::process::kill()
+}
+
+/// Returns the process the current thread belongs to, if any.
+pub fn current_process() -> Option<Pid> {
+    without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.threads.get(&scheduler.current)?.process
+    })
+}
+
+/// Like `current_process`, but returns `None` if the scheduler is locked.
+pub fn try_current_process() -> Option<Pid> {
+    without_interrupts(|| {
+        let scheduler = SCHEDULER.try_lock()?;
+        scheduler.threads.get(&scheduler.current)?.process
+    })
+}
+
+/// Returns the CPU time of the current thread, or with `whole_process` of every thread
+/// in its process, in nanoseconds.
+pub fn cpu_time(whole_process: bool) -> u64 {
+    without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let current = &scheduler.threads[&scheduler.current];
+        let running = crate::time::monotonic() - scheduler.switched_at;
+        if !whole_process || current.process.is_none() {
+            return current.cpu_time + running;
+        }
+        scheduler
+            .threads
+            .values()
+            .filter(|thread| thread.process == current.process)
+            .map(|thread| thread.cpu_time)
+            .sum::<u64>()
+            + running
+    })
+}
+
+/// Picks the next ready thread and makes it current, returning the contexts to switch between.
+fn reschedule(scheduler: &mut Scheduler) -> Option<(*mut Context, *const Context)> {
+    let next = scheduler.ready.pop_front()?;
+    let current = scheduler.current;
+    let now = crate::time::monotonic();
+    let running = now - scheduler.switched_at;
+    scheduler.switched_at = now;
+    let thread = scheduler.threads.get_mut(&current).unwrap();
+    thread.cpu_time += running;
+    thread.kernel_depth = KERNEL_DEPTH.load(Ordering::Relaxed);
+    if thread.kernel_depth == 0 && thread.budget.is_some_and(|budget| thread.cpu_time > budget) {
+        thread.killed = true;
+    }
+    if thread.state == State::Running {
+        thread.state = State::Ready;
+        scheduler.ready.push_back(current);
+    }
+    let from = &mut scheduler.threads.get_mut(&current).unwrap().context as *mut Context;
+    let thread = scheduler.threads.get_mut(&next).unwrap();
+    if thread.killed
+        && let Some(stack) = &mut thread.stack
+    {
+        thread.context.restart(stack, killed_entry);
+        thread.killed = false;
+        thread.budget = None;
+        thread.kernel_depth = 1;
+    }
+    KERNEL_DEPTH.store(thread.kernel_depth, Ordering::Relaxed);
+    thread.state = State::Running;
+    let to = &thread.context as *const Context;
+    scheduler.current = next;
+    Some((from, to))
+}
+
+/// Gives up the CPU to the next ready thread, if there is one.
+pub fn yield_now() {
+    without_interrupts(|| {
+        let switch = {
+            let mut scheduler = SCHEDULER.lock();
+            // Threads that already exited aren't running on their stacks anymore.
+            let current = scheduler.current;
+            scheduler
+                .threads
+                .retain(|tid, thread| *tid == current || thread.state != State::Exited);
+            reschedule(&mut scheduler)
+        };
+        if let Some((from, to)) = switch {
+            arch::switch(from, to);
+        }
+    });
+}
+
+/// Switches away from the current thread because its time slice is up.
+///
+/// # Safety
+///
+/// Called from the timer interrupt. Does nothing if the interrupted thread was in the scheduler.
+pub fn preempt() {
+    let switch = match SCHEDULER.try_lock() {
+        Some(mut scheduler) => reschedule(&mut scheduler),
+        None => None,
+    };
+    if let Some((from, to)) = switch {
+        arch::switch(from, to);
+    }
+}
+
+/// Ends the current thread. Its stack is freed by whichever thread yields next.
+pub fn exit() -> ! {
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler.threads.get_mut(&current).unwrap().state = State::Exited;
+    });
+    yield_now();
+    unreachable!("exited thread was scheduled again");
+}
@@ -0,0 +1,49 @@
+use crate::println;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/time.rs")]
+pub mod arch;
+#[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/hpet.rs")]
+pub mod hpet;
+#[cfg_attr(target_arch = "x86_64", path = "arch/x86_64/rtc.rs")]
+pub mod rtc;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Nanoseconds since the Unix epoch when the monotonic clock read zero.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Calibrates the monotonic clock and sets the wall clock from the RTC.
+///
+/// # Safety
+///
+/// Interrupts must be off and `acpi_init` must have run. See `arch::time_init`.
+pub fn time_init() {
+    arch::time_init();
+    let wall_clock = rtc::read().unwrap_or_else(|| {
+        println!("rtc: the clock holds an invalid time, starting from the epoch");
+        0
+    });
+    let boot_time = (wall_clock * NANOSECONDS_PER_SECOND).saturating_sub(monotonic());
+    BOOT_TIME.store(boot_time, Ordering::Relaxed);
+}
+
+/// Returns nanoseconds since boot. Never goes backwards.
+pub fn monotonic() -> u64 {
+    arch::nanoseconds()
+}
+
+/// Returns nanoseconds since the Unix epoch.
+pub fn realtime() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + monotonic()
+}
+
+/// Returns the resolution of both clocks in nanoseconds.
+pub fn resolution() -> u64 {
+    arch::resolution()
+}
+
+/// Returns the kernel clock that file timestamps come from: nanoseconds since the Unix epoch.
+pub fn now() -> u64 {
+    realtime()
+}
@@ -0,0 +1,471 @@
+use crate::errno::Errno;
+use crate::fs::{Descriptor, FileType, Metadata, OpenFlags};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use wasm3::error::{Error, Result};
+use wasm3::{CallContext, Module, WasmArgs, WasmType};
+use wasm3_sys::{IM3ImportContext, IM3Runtime, m3Err_trapExit};
+
+const WASI: &str = "wasi_snapshot_preview1";
+const ERRNO_SUCCESS: u32 = 0;
+
+const OFLAGS_CREAT: u32 = 1;
+const OFLAGS_DIRECTORY: u32 = 2;
+const OFLAGS_EXCL: u32 = 4;
+const OFLAGS_TRUNC: u32 = 8;
+const FDFLAGS_APPEND: u32 = 1;
+const RIGHTS_FD_READ: u64 = 1 << 1;
+const RIGHTS_FD_WRITE: u64 = 1 << 6;
+
+const PREOPENTYPE_DIR: u8 = 0;
+
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+
+/// The argv and envp a Wasm program sees.
+pub struct Environ {
+    pub args: Vec<String>,
+    pub vars: Vec<String>,
+}
+
+/// Links the kernel's own WASI functions, replacing the ones from `link_wasi`.
+///
+/// # Safety
+///
+/// Must be called after `link_wasi`, or wasm3 will link over these.
+pub fn link(module: &mut Module, environ: Arc<Environ>) -> Result<()> {
+    let args = environ.clone();
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "args_sizes_get",
+        move |cc: &CallContext, (count, size): (u32, u32)| {
+            errno(sizes_get(cc, &args.args, count, size))
+        },
+    ))?;
+    let args = environ.clone();
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "args_get",
+        move |cc: &CallContext, (pointers, buffer): (u32, u32)| {
+            errno(strings_get(cc, &args.args, pointers, buffer))
+        },
+    ))?;
+    let vars = environ.clone();
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "environ_sizes_get",
+        move |cc: &CallContext, (count, size): (u32, u32)| {
+            errno(sizes_get(cc, &vars.vars, count, size))
+        },
+    ))?;
+    let vars = environ;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "environ_get",
+        move |cc: &CallContext, (pointers, buffer): (u32, u32)| {
+            errno(strings_get(cc, &vars.vars, pointers, buffer))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "fd_renumber",
+        |_: &CallContext, (fd, to): (u32, u32)| {
+            // Dropped outside the process table's lock, since closing the last descriptor to a
+            // file can release the memory it was charged.
+            let replaced =
+                crate::process::with_current(|process| process.fds.renumber(fd as i32, to as i32));
+            errno(replaced.flatten().map(drop).ok_or(Errno::BadF))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "fd_prestat_get",
+        |cc: &CallContext, (fd, prestat): (u32, u32)| errno(fd_prestat_get(cc, fd, prestat)),
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "fd_prestat_dir_name",
+        |cc: &CallContext, (fd, path, length): (u32, u32, u32)| {
+            errno(fd_prestat_dir_name(cc, fd, path, length))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "path_open",
+        |cc: &CallContext,
+         (fd, _lookup_flags, path, length, oflags, rights, _inheriting, fdflags, opened): (
+            u32,
+            u32,
+            u32,
+            u32,
+            u32,
+            u64,
+            u64,
+            u32,
+            u32,
+        )| {
+            let open_flags = open_flags(oflags, rights, fdflags);
+            errno(path_open(cc, fd, path, length, open_flags, opened))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "fd_filestat_get",
+        |cc: &CallContext, (fd, filestat): (u32, u32)| errno(fd_filestat_get(cc, fd, filestat)),
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "path_filestat_get",
+        |cc: &CallContext, (fd, _flags, path, length, filestat): (u32, u32, u32, u32, u32)| {
+            errno(path_filestat_get(cc, fd, path, length, filestat))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "fd_filestat_set_size",
+        |_: &CallContext, (fd, size): (u32, u64)| errno(fd_filestat_set_size(fd, size)),
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "path_unlink_file",
+        |cc: &CallContext, (fd, path, length): (u32, u32, u32)| {
+            errno(path_unlink_file(cc, fd, path, length))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "path_rename",
+        |cc: &CallContext,
+         (fd, path, length, new_fd, new_path, new_length): (u32, u32, u32, u32, u32, u32)| {
+            errno(path_rename(
+                cc, fd, path, length, new_fd, new_path, new_length,
+            ))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "path_create_directory",
+        |cc: &CallContext, (fd, path, length): (u32, u32, u32)| {
+            errno(path_create_directory(cc, fd, path, length))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "path_remove_directory",
+        |cc: &CallContext, (fd, path, length): (u32, u32, u32)| {
+            errno(path_remove_directory(cc, fd, path, length))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "fd_readdir",
+        |cc: &CallContext, (fd, buffer, length, cookie, used): (u32, u32, u32, u64, u32)| {
+            errno(fd_readdir(cc, fd, buffer, length, cookie, used))
+        },
+    ))?;
+    ignore_missing(link_closure(
+        module,
+        WASI,
+        "sched_yield",
+        |_: &CallContext, ()| {
+            crate::sched::yield_now();
+            ERRNO_SUCCESS
+        },
+    ))?;
+    ignore_missing(module.link_function::<u32, ()>(WASI, "proc_exit", proc_exit))
+}
+
+/// Records the exit code on the process and stops the guest with wasm3's exit trap,
+/// the only trap the loader counts as a clean exit. A closure can't trap, so this is a raw function.
+unsafe extern "C" fn proc_exit(
+    _runtime: IM3Runtime,
+    _context: IM3ImportContext,
+    stack: *mut u64,
+    _memory: *mut c_void,
+) -> *const c_void {
+    let _kernel = crate::sched::KernelSection::enter();
+    let code = unsafe { *stack } as u32;
+    crate::process::with_current(|process| process.exit_code = Some(code as i32));
+    unsafe { m3Err_trapExit }.cast()
+}
+
+/// Links a host function that runs in a `KernelSection`, so its thread can't be killed halfway through.
+/// The process notes which one it's in, so a panic can say.
+pub fn link_closure<Args, Ret, F>(
+    module: &mut Module,
+    module_name: &'static str,
+    name: &'static str,
+    mut function: F,
+) -> Result<()>
+where
+    Args: WasmArgs,
+    Ret: WasmType,
+    F: for<'cc> FnMut(&'cc CallContext, Args) -> Ret + 'static,
+{
+    module.link_closure(module_name, name, move |cc: &CallContext, args: Args| {
+        let _kernel = crate::sched::KernelSection::enter();
+        crate::process::with_current(|process| process.host_call = Some((module_name, name)));
+        let result = function(cc, args);
+        crate::process::with_current(|process| process.host_call = None);
+        result
+    })
+}
+
+/// Modules only import what they use, so a missing import isn't an error.
+pub fn ignore_missing(result: Result<()>) -> Result<()> {
+    match result {
+        Err(Error::FunctionNotFound) => Ok(()),
+        result => result,
+    }
+}
+
+/// What the functions behind the imports return, before it becomes a WASI errno.
+pub type Outcome<T = ()> = core::result::Result<T, Errno>;
+
+pub fn errno(outcome: Outcome) -> u32 {
+    match outcome {
+        Ok(()) => ERRNO_SUCCESS,
+        Err(errno) => errno.wasi(),
+    }
+}
+
+/// The guest's linear memory. Callers borrow it for the length of one host call.
+pub fn memory(cc: &CallContext) -> *mut [u8] {
+    unsafe { cc.memory_mut() }
+}
+
+pub fn read_bytes(memory: &[u8], address: u32, length: u32) -> Outcome<&[u8]> {
+    let address = address as usize;
+    let end = address.checked_add(length as usize).ok_or(Errno::Fault)?;
+    memory.get(address..end).ok_or(Errno::Fault)
+}
+
+pub fn read_string(memory: &[u8], address: u32, length: u32) -> Outcome<&str> {
+    core::str::from_utf8(read_bytes(memory, address, length)?).map_err(|_| Errno::Inval)
+}
+
+pub fn write_bytes(memory: &mut [u8], address: u32, bytes: &[u8]) -> Outcome {
+    let address = address as usize;
+    let end = address.checked_add(bytes.len()).ok_or(Errno::Fault)?;
+    memory
+        .get_mut(address..end)
+        .ok_or(Errno::Fault)?
+        .copy_from_slice(bytes);
+    Ok(())
+}
+
+pub fn write_u32(memory: &mut [u8], address: u32, value: u32) -> Outcome {
+    write_bytes(memory, address, &value.to_le_bytes())
+}
+
+fn sizes_get(cc: &CallContext, strings: &[String], count: u32, size: u32) -> Outcome {
+    let memory = unsafe { &mut *memory(cc) };
+    let total: usize = strings.iter().map(|string| string.len() + 1).sum();
+    let count_value = u32::try_from(strings.len()).map_err(|_| Errno::Overflow)?;
+    let total = u32::try_from(total).map_err(|_| Errno::Overflow)?;
+    write_u32(memory, count, count_value)?;
+    write_u32(memory, size, total)
+}
+
+fn strings_get(cc: &CallContext, strings: &[String], pointers: u32, buffer: u32) -> Outcome {
+    let memory = unsafe { &mut *memory(cc) };
+    let mut pointer = pointers;
+    let mut address = buffer;
+    for string in strings {
+        let length = u32::try_from(string.len()).map_err(|_| Errno::Overflow)?;
+        let end = address.checked_add(length).ok_or(Errno::Fault)?;
+        write_u32(memory, pointer, address)?;
+        write_bytes(memory, address, string.as_bytes())?;
+        write_bytes(memory, end, &[0])?;
+        pointer = pointer.checked_add(4).ok_or(Errno::Fault)?;
+        address = end.checked_add(1).ok_or(Errno::Fault)?;
+    }
+    Ok(())
+}
+
+/// Resolves a guest path against the directory open at `fd`, without leaving it.
+pub fn resolve(cc: &CallContext, fd: u32, path: u32, length: u32) -> Outcome<String> {
+    let Some(Descriptor::File(directory)) = crate::process::descriptor(fd as i32) else {
+        return Err(Errno::BadF);
+    };
+    let path = read_string(unsafe { &*memory(cc) }, path, length)?;
+    crate::fs::join_beneath(&crate::fs::path(&directory), path)
+}
+
+fn preopen_name(fd: u32) -> Outcome<String> {
+    crate::process::with_current(|process| process.preopens.get(&(fd as i32)).cloned())
+        .flatten()
+        .ok_or(Errno::BadF)
+}
+
+/// Describes a preopened directory. wasi-libc calls this on fds 3 and up until it fails.
+fn fd_prestat_get(cc: &CallContext, fd: u32, prestat: u32) -> Outcome {
+    let name = preopen_name(fd)?;
+    let mut bytes = [0; 8];
+    bytes[0] = PREOPENTYPE_DIR;
+    bytes[4..].copy_from_slice(&(name.len() as u32).to_le_bytes());
+    write_bytes(unsafe { &mut *memory(cc) }, prestat, &bytes)
+}
+
+fn fd_prestat_dir_name(cc: &CallContext, fd: u32, path: u32, length: u32) -> Outcome {
+    let name = preopen_name(fd)?;
+    let length = name.len().min(length as usize);
+    write_bytes(
+        unsafe { &mut *memory(cc) },
+        path,
+        &name.as_bytes()[..length],
+    )
+}
+
+/// Turns `path_open`'s oflags, rights and fdflags into the flags `fs::open` takes.
+fn open_flags(oflags: u32, rights: u64, fdflags: u32) -> OpenFlags {
+    OpenFlags {
+        read: rights & RIGHTS_FD_READ != 0,
+        write: rights & RIGHTS_FD_WRITE != 0,
+        append: fdflags & FDFLAGS_APPEND != 0,
+        create: oflags & OFLAGS_CREAT != 0,
+        exclude: oflags & OFLAGS_EXCL != 0,
+        truncate: oflags & OFLAGS_TRUNC != 0,
+        directory: oflags & OFLAGS_DIRECTORY != 0,
+    }
+}
+
+/// Opens a path beneath the directory at `fd` and writes the new fd to `opened`.
+fn path_open(
+    cc: &CallContext,
+    fd: u32,
+    path: u32,
+    length: u32,
+    open_flags: OpenFlags,
+    opened: u32,
+) -> Outcome {
+    let path = resolve(cc, fd, path, length)?;
+    let descriptor = crate::fs::open(path, open_flags)?;
+    let new_fd = crate::process::with_current(|process| process.fds.insert(descriptor))
+        .ok_or(Errno::BadF)??;
+    write_u32(unsafe { &mut *memory(cc) }, opened, new_fd as u32)
+}
+
+fn filetype(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::Directory => FILETYPE_DIRECTORY,
+        FileType::File => FILETYPE_REGULAR_FILE,
+    }
+}
+
+/// Writes a `filestat`. The console has no inode, so it's a character device with zeroes elsewhere.
+fn write_filestat(cc: &CallContext, filestat: u32, metadata: Option<Metadata>) -> Outcome {
+    let mut bytes = [0; 64];
+    match metadata {
+        None => bytes[16] = FILETYPE_CHARACTER_DEVICE,
+        Some(metadata) => {
+            bytes[8..16].copy_from_slice(&metadata.id.to_le_bytes());
+            bytes[16] = filetype(metadata.file_type);
+            bytes[24..32].copy_from_slice(&metadata.links.to_le_bytes());
+            bytes[32..40].copy_from_slice(&(metadata.size as u64).to_le_bytes());
+            bytes[40..48].copy_from_slice(&metadata.times.accessed.to_le_bytes());
+            bytes[48..56].copy_from_slice(&metadata.times.modified.to_le_bytes());
+            bytes[56..64].copy_from_slice(&metadata.times.changed.to_le_bytes());
+        }
+    }
+    write_bytes(unsafe { &mut *memory(cc) }, filestat, &bytes)
+}
+
+fn fd_filestat_get(cc: &CallContext, fd: u32, filestat: u32) -> Outcome {
+    match crate::process::descriptor(fd as i32) {
+        None => Err(Errno::BadF),
+        Some(Descriptor::Console) => write_filestat(cc, filestat, None),
+        Some(Descriptor::File(file)) => {
+            write_filestat(cc, filestat, Some(crate::fs::metadata(&file)))
+        }
+    }
+}
+
+fn path_filestat_get(cc: &CallContext, fd: u32, path: u32, length: u32, filestat: u32) -> Outcome {
+    let path = resolve(cc, fd, path, length)?;
+    write_filestat(cc, filestat, Some(crate::fs::stat(&path)?))
+}
+
+fn fd_filestat_set_size(fd: u32, size: u64) -> Outcome {
+    let Some(Descriptor::File(file)) = crate::process::descriptor(fd as i32) else {
+        return Err(Errno::BadF);
+    };
+    crate::fs::truncate(&file, usize::try_from(size).map_err(|_| Errno::FBig)?)
+}
+
+fn path_unlink_file(cc: &CallContext, fd: u32, path: u32, length: u32) -> Outcome {
+    crate::fs::unlink(&resolve(cc, fd, path, length)?)
+}
+
+fn path_rename(
+    cc: &CallContext,
+    fd: u32,
+    path: u32,
+    length: u32,
+    new_fd: u32,
+    new_path: u32,
+    new_length: u32,
+) -> Outcome {
+    let path = resolve(cc, fd, path, length)?;
+    let new_path = resolve(cc, new_fd, new_path, new_length)?;
+    crate::fs::rename(&path, &new_path)
+}
+
+fn path_create_directory(cc: &CallContext, fd: u32, path: u32, length: u32) -> Outcome {
+    crate::fs::mkdir(&resolve(cc, fd, path, length)?)
+}
+
+fn path_remove_directory(cc: &CallContext, fd: u32, path: u32, length: u32) -> Outcome {
+    crate::fs::rmdir(&resolve(cc, fd, path, length)?)
+}
+
+/// Fills `buffer` with `dirent`s starting at entry `cookie`.
+/// A full buffer tells the guest to call again with the last entry's `d_next`.
+fn fd_readdir(
+    cc: &CallContext,
+    fd: u32,
+    buffer: u32,
+    length: u32,
+    cookie: u64,
+    used: u32,
+) -> Outcome {
+    let Some(Descriptor::File(directory)) = crate::process::descriptor(fd as i32) else {
+        return Err(Errno::BadF);
+    };
+    let entries = crate::fs::readdir(&directory)?;
+    let mut dirents = Vec::new();
+    for (index, entry) in entries.iter().enumerate().skip(cookie as usize) {
+        dirents.extend_from_slice(&(index as u64 + 1).to_le_bytes());
+        dirents.extend_from_slice(&0u64.to_le_bytes());
+        dirents.extend_from_slice(&(entry.name.len() as u32).to_le_bytes());
+        dirents.push(filetype(entry.file_type));
+        dirents.extend_from_slice(&[0; 3]);
+        dirents.extend_from_slice(entry.name.as_bytes());
+        if dirents.len() >= length as usize {
+            break;
+        }
+    }
+    dirents.truncate(length as usize);
+    let memory = unsafe { &mut *memory(cc) };
+    write_bytes(memory, buffer, &dirents)?;
+    write_u32(memory, used, dirents.len() as u32)
+}